@@ -0,0 +1,90 @@
+//! Retrieval and insertion through the HTTP interface with the Native format, versus the
+//! ArrowStream format. Requires a Clickhouse server on `localhost:8123`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use polarhouse::{ClickhouseTable, ClientGeneric, ColumnarFormat, HttpClient, HttpClientBuilder};
+
+fn client(format: ColumnarFormat) -> HttpClient {
+    HttpClientBuilder::new("http://localhost:8123")
+        .format(format)
+        .build()
+        .unwrap()
+}
+
+fn query(rows: usize) -> String {
+    format!(
+        "SELECT number AS id, number * 1.5 AS value, toString(number) AS name, \
+         number % 2 = 0 AS even FROM numbers({})",
+        rows
+    )
+}
+
+fn bench(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let formats = [ColumnarFormat::Native, ColumnarFormat::ArrowStream];
+
+    let mut group = c.benchmark_group("retrieve");
+    group.sample_size(10);
+    for rows in [1_000_000, 5_000_000] {
+        for format in formats {
+            let ch = client(format);
+            group.bench_with_input(BenchmarkId::new(format.name(), rows), &rows, |b, &rows| {
+                b.to_async(&rt).iter(|| async {
+                    polarhouse::get_df_query(query(rows), Default::default(), &ch)
+                        .await
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for rows in [1_000_000, 5_000_000] {
+        let df = rt
+            .block_on(polarhouse::get_df_query(
+                query(rows),
+                Default::default(),
+                &client(ColumnarFormat::Native),
+            ))
+            .unwrap();
+        for format in formats {
+            let ch = client(format);
+            let table = ClickhouseTable::from_polars_schema(
+                &format!("bench_formats_{}", format.name()),
+                df.schema(),
+                Default::default(),
+                Vec::<String>::new(),
+            )
+            .unwrap();
+            rt.block_on(async {
+                ch.execute(format!("DROP TABLE IF EXISTS {}", table.name))
+                    .await?;
+                table
+                    .create(
+                        polarhouse::TableCreationOptions {
+                            primary_keys: &["id"],
+                            ..Default::default()
+                        },
+                        &ch,
+                    )
+                    .await
+            })
+            .unwrap();
+            group.bench_with_input(BenchmarkId::new(format.name(), rows), &df, |b, df| {
+                b.to_async(&rt).iter(|| async {
+                    table
+                        .insert_df(df.clone(), Default::default(), &ch)
+                        .await
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! Retrieval with `get_df_query`, which appends the blocks as chunks of the dataframe, with and
+//! without rechunking them once all blocks are received, and with the conversion through
+//! `klickhouse::Value` rather than the columnar codec. Requires a Clickhouse server on
+//! `localhost:8123`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use polarhouse::{GetOptions, HttpClientBuilder};
+
+fn query(rows: usize) -> String {
+    format!(
+        "SELECT number AS id, number * 1.5 AS value, toString(number) AS name FROM numbers({})",
+        rows
+    )
+}
+
+fn bench(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let ch = HttpClientBuilder::new("http://localhost:8123")
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("get_df_query");
+    group.sample_size(10);
+    for rows in [1_000_000, 5_000_000] {
+        for (name, rechunk, columnar) in [
+            ("rechunk", true, true),
+            ("chunks", false, true),
+            ("values", true, false),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, rows), &rows, |b, &rows| {
+                b.to_async(&rt).iter(|| async {
+                    polarhouse::get_df_query(
+                        query(rows),
+                        GetOptions {
+                            rechunk,
+                            columnar,
+                            ..Default::default()
+                        },
+                        &ch,
+                    )
+                    .await
+                    .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
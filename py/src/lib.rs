@@ -51,6 +51,7 @@ impl Client {
                 } else {
                     Some(&password)
                 },
+                Default::default(),
             )
             .await
             .map_err(|e| PyException::new_err(format!("Failed to connect to Clickhouse: {}", e)))?;
@@ -0,0 +1,234 @@
+//! Arrow IPC streams, for the `ArrowStream` format of the HTTP interface.
+
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use polars::export::arrow::{
+    array::Array,
+    chunk::Chunk,
+    compute::cast,
+    datatypes::{ArrowDataType, ArrowSchema, Field},
+    io::ipc,
+};
+use polars::prelude::*;
+
+use crate::{ClickhouseType, Error, SeriesBlock};
+
+/// Read the record batches of a stream as blocks.
+///
+/// The Clickhouse types are inferred from the Arrow types, following the Arrow output format of
+/// Clickhouse.
+pub(crate) fn read_blocks<'a>(
+    mut reader: impl BufRead + 'a,
+) -> Box<dyn Iterator<Item = Result<SeriesBlock, Error>> + 'a> {
+    match reader.fill_buf() {
+        // Empty response
+        Ok(buf) if buf.is_empty() => return Box::new(std::iter::empty()),
+        Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+        _ => {}
+    }
+    let metadata = match ipc::read::read_stream_metadata(&mut reader) {
+        Ok(metadata) => metadata,
+        Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+    };
+    let fields = metadata.schema.fields.clone();
+    Box::new(
+        ipc::read::StreamReader::new(reader, metadata, None).filter_map(move |state| match state {
+            Ok(ipc::read::StreamState::Some(chunk)) => Some(read_chunk(&fields, chunk)),
+            // Only returned by non-blocking readers.
+            Ok(ipc::read::StreamState::Waiting) => None,
+            Err(e) => Some(Err(e.into())),
+        }),
+    )
+}
+
+fn read_chunk(fields: &[Field], chunk: Chunk<Box<dyn Array>>) -> Result<SeriesBlock, Error> {
+    let mut block = SeriesBlock {
+        rows: chunk.len(),
+        column_types: Default::default(),
+        column_data: Default::default(),
+    };
+    for (field, array) in fields.iter().zip(chunk.into_arrays()) {
+        let (mut type_, series) = read_column(field, array)?;
+        if field.is_nullable {
+            type_ = type_.nullable();
+        }
+        block.column_types.insert(field.name.clone(), type_);
+        block.column_data.insert(field.name.clone(), series);
+    }
+    Ok(block)
+}
+
+/// Clickhouse type of an Arrow column, with the column as a series of its representation.
+///
+/// `DateTime` and `Date` columns are sent as `UInt32` and `UInt16`, which cannot be told apart
+/// from integers; they are converted once their types are known, see
+/// [ColumnarFormat::ArrowStream](crate::ColumnarFormat::ArrowStream). Decimals are retrieved as
+/// floats, timestamps (from `DateTime64`) in milliseconds, and fixed-size binaries (from
+/// `FixedString` and `UUID`) as binaries.
+fn read_column(field: &Field, array: Box<dyn Array>) -> Result<(ClickhouseType, Series), Error> {
+    use klickhouse::Type as T;
+    if let ArrowDataType::FixedSizeBinary(size) = array.data_type() {
+        let size = *size;
+        let array = cast::cast(
+            array.as_ref(),
+            &ArrowDataType::LargeBinary,
+            Default::default(),
+        )?;
+        let series = Series::try_from((field.name.as_str(), array))?;
+        return Ok((T::FixedString(size).into(), series));
+    }
+    let series = Series::try_from((field.name.as_str(), array))?;
+    Ok(match series.dtype() {
+        DataType::Decimal(precision, scale) => {
+            let scale = scale.unwrap_or_default();
+            let type_ = match precision.unwrap_or(38) {
+                0..=9 => T::Decimal32(scale),
+                10..=18 => T::Decimal64(scale),
+                _ => T::Decimal128(scale),
+            };
+            (type_.into(), series.cast(&DataType::Float64)?)
+        }
+        DataType::Datetime(_, tz) => {
+            let type_ = match tz {
+                Some(tz) => format!("DateTime('{}')", tz),
+                None => "DateTime".into(),
+            };
+            let dtype = DataType::Datetime(TimeUnit::Milliseconds, tz.clone());
+            (type_.parse()?, series.cast(&dtype)?)
+        }
+        dtype => (ClickhouseType::try_from(dtype)?, series),
+    })
+}
+
+/// Buffer shared with the writer, from which the encoded bytes are taken after each batch.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encoder of blocks into an Arrow IPC stream, one record batch per block.
+///
+/// The first batch is preceded by the schema, and [StreamEncoder::finish] returns the end of the
+/// stream.
+pub(crate) struct StreamEncoder {
+    writer: ipc::write::StreamWriter<SharedBuffer>,
+    buffer: SharedBuffer,
+    started: bool,
+    pub(crate) finished: bool,
+}
+impl Default for StreamEncoder {
+    fn default() -> Self {
+        let buffer = SharedBuffer::default();
+        Self {
+            writer: ipc::write::StreamWriter::new(
+                buffer.clone(),
+                ipc::write::WriteOptions { compression: None },
+            ),
+            buffer,
+            started: false,
+            finished: false,
+        }
+    }
+}
+impl StreamEncoder {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.buffer.0.lock().unwrap())
+    }
+    /// Encode the columns of a block, given with their names.
+    pub(crate) fn write(
+        &mut self,
+        columns: Vec<(String, Box<dyn Array>)>,
+    ) -> Result<Vec<u8>, Error> {
+        if !self.started {
+            let schema = ArrowSchema::from(
+                columns
+                    .iter()
+                    .map(|(name, array)| Field::new(name, array.data_type().clone(), true))
+                    .collect::<Vec<_>>(),
+            );
+            self.writer.start(&schema, None)?;
+            self.started = true;
+        }
+        let arrays = columns.into_iter().map(|(_, array)| array).collect();
+        self.writer.write(&Chunk::new(arrays), None)?;
+        Ok(self.take())
+    }
+    /// End of the stream.
+    pub(crate) fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        self.finished = true;
+        self.writer.finish()?;
+        Ok(self.take())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn columns(series: &[Series]) -> Vec<(String, Box<dyn Array>)> {
+        series
+            .iter()
+            .map(|s| (s.name().to_string(), s.rechunk().to_arrow(0, false)))
+            .collect()
+    }
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let a = Series::new("a", [Some(1i32), None, Some(3)]);
+        let b = Series::new("b", ["x", "y", "z"]);
+        let mut encoder = StreamEncoder::default();
+        let mut stream = encoder.write(columns(&[a.slice(0, 2), b.slice(0, 2)]))?;
+        stream.extend(encoder.write(columns(&[a.slice(2, 1), b.slice(2, 1)]))?);
+        stream.extend(encoder.finish()?);
+
+        let blocks: Vec<SeriesBlock> =
+            read_blocks(std::io::Cursor::new(stream)).collect::<Result<_, _>>()?;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].rows, 2);
+        assert!(blocks[1].column_data["a"].equals_missing(&a.slice(2, 1)));
+        assert_eq!(
+            blocks[0].column_types["a"],
+            ClickhouseType::try_from(&DataType::Int32)?.nullable()
+        );
+        assert!(blocks[1].column_data["b"].equals_missing(&b.slice(2, 1)));
+        Ok(())
+    }
+    #[test]
+    fn clickhouse_types() -> anyhow::Result<()> {
+        let decimal = Int128Chunked::from_slice("decimal", &[12345, -1])
+            .into_decimal(Some(18), 2)?
+            .into_series();
+        let timestamp = Series::new("timestamp", &[1_700_000_000_123_456i64]).cast(
+            &DataType::Datetime(TimeUnit::Microseconds, Some("UTC".into())),
+        )?;
+        let datetime = Series::new("datetime", &[1_700_000_000u32]);
+        let mut encoder = StreamEncoder::default();
+        let mut stream =
+            encoder.write(columns(&[decimal.slice(0, 1), timestamp, datetime.clone()]))?;
+        stream.extend(encoder.finish()?);
+
+        let block = read_blocks(std::io::Cursor::new(stream)).next().unwrap()?;
+        assert_eq!(
+            block.column_types["decimal"],
+            "Decimal(18, 2)".parse::<ClickhouseType>()?.nullable()
+        );
+        assert!(block.column_data["decimal"].equals(&Series::new("decimal", &[123.45])));
+        assert_eq!(
+            block.column_types["timestamp"],
+            "DateTime('UTC')".parse::<ClickhouseType>()?.nullable()
+        );
+        assert_eq!(
+            block.column_data["timestamp"].datetime()?.get(0),
+            Some(1_700_000_000_123)
+        );
+        // Indistinguishable from integers.
+        assert!(block.column_data["datetime"].equals(&datetime));
+        Ok(())
+    }
+}
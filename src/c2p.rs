@@ -1,25 +1,181 @@
 //! Clickhouse to Polars conversions
 
 use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use klickhouse::IndexMap;
 use polars::prelude::*;
+use rayon::prelude::*;
 use tracing::*;
 
-use super::{structs, ClickhouseType, Error};
-use crate::clickhouse::ClientGeneric;
+use super::{structs, CancellationToken, ClickhouseType, Error};
+use crate::{
+    clickhouse::{ClientGeneric, ColumnarFormat},
+    native::SeriesBlock,
+    query::{Params, ProgressHandler, QueryOptions, Settings},
+};
 
+#[derive(Clone)]
 pub struct GetOptions {
     pub unflatten_structs: bool,
     pub types: IndexMap<String, ClickhouseType>,
+    /// Type overrides for columns whose name matches a pattern, e.g. `is_*` to [ClickhouseType::Bool].
+    /// The first matching rule applies, and exact entries in `types` take precedence.
+    pub overrides: Vec<(ColumnPattern, ClickhouseType)>,
+    /// Resolve the exact result types with `DESCRIBE (query)` before running the query, so that
+    /// e.g. booleans in computed columns and joins are retrieved as such. Always done with the
+    /// [ColumnarFormat::ArrowStream] format.
+    pub describe: bool,
+    /// Cache of the types resolved with `describe`, see [DescribeCache].
+    pub describe_cache: Option<DescribeCache>,
+    /// Decode blocks directly into series when the client supports it (see
+    /// [ClientGeneric::supports_columnar]), rather than through [klickhouse::Value].
+    pub columnar: bool,
+    /// Regroup the dataframes yielded by [get_df_stream] into batches of this many rows, rather
+    /// than one per received block.
+    pub batch_rows: Option<usize>,
+    /// Rechunk the dataframe returned by [get_df_query] into contiguous memory once all blocks are
+    /// received. Otherwise, it keeps one chunk per received block.
+    pub rechunk: bool,
+    /// Check the type of every received value, rather than only the column types in the header of
+    /// each block. This only applies when blocks are not decoded by the columnar codec.
+    pub validate_values: bool,
+    /// Clickhouse settings for the query, e.g. `max_threads` or `join_use_nulls`.
+    pub settings: Settings,
+    /// Values of the `{name:Type}` placeholders of the query, bound by the server rather than
+    /// formatted into the query.
+    pub params: Params,
+    /// Receive the progress of the query, see [ProgressHandler].
+    pub progress: Option<ProgressHandler>,
+    /// Id of the query on the server, see [QueryOptions::query_id].
+    pub query_id: Option<String>,
+    /// Abandon the query with [Error::Cancelled] when the token is cancelled.
+    pub cancel: Option<CancellationToken>,
+    /// Abandon the query with [Error::Timeout] after this duration, including the retrieval of the
+    /// results. The server is also asked to stop the query with the `max_execution_time` setting,
+    /// unless it is set explicitly.
+    pub timeout: Option<Duration>,
 }
 impl Default for GetOptions {
     fn default() -> Self {
         Self {
             unflatten_structs: true,
             types: Default::default(),
+            overrides: Default::default(),
+            describe: false,
+            describe_cache: None,
+            columnar: true,
+            batch_rows: None,
+            rechunk: true,
+            validate_values: false,
+            settings: Default::default(),
+            params: Default::default(),
+            progress: None,
+            query_id: None,
+            cancel: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Column name pattern, see [GetOptions::overrides].
+#[derive(Clone, Debug)]
+pub enum ColumnPattern {
+    Exact(String),
+    Regex(regex::Regex),
+}
+impl ColumnPattern {
+    /// Glob pattern, where `*` matches any sequence of characters.
+    pub fn glob(pattern: &str) -> Self {
+        let regex = pattern.split('*').map(regex::escape).join(".*");
+        // All other characters are escaped, hence the regex is always valid.
+        Self::Regex(regex::Regex::new(&format!("^{}$", regex)).unwrap())
+    }
+    pub fn matches(&self, column: &str) -> bool {
+        match self {
+            ColumnPattern::Exact(name) => name == column,
+            ColumnPattern::Regex(regex) => regex.is_match(column),
+        }
+    }
+}
+
+/// Result types, keyed by query and parameters.
+type DescribeEntries = IndexMap<(String, String), IndexMap<String, ClickhouseType>>;
+
+/// Cache of the result types of queries resolved with [GetOptions::describe], keyed by the query
+/// and its parameters, holding at most `capacity` queries (the oldest are evicted first).
+///
+/// The types depend on the server, the database, and the schema of the tables: a cache should
+/// only be shared between queries of the same client, and cleared when the schema changes.
+/// Clones share the same entries.
+#[derive(Clone, Debug)]
+pub struct DescribeCache {
+    entries: Arc<Mutex<DescribeEntries>>,
+    capacity: usize,
+}
+impl DescribeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            capacity,
+        }
+    }
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+    fn key(query: &str, params: &Params) -> (String, String) {
+        (query.to_string(), format!("{:?}", params))
+    }
+    fn get(&self, query: &str, params: &Params) -> Option<IndexMap<String, ClickhouseType>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&Self::key(query, params)).cloned()
+    }
+    fn insert(&self, query: &str, params: &Params, types: IndexMap<String, ClickhouseType>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(Self::key(query, params), types);
+        while entries.len() > self.capacity {
+            entries.shift_remove_index(0);
+        }
+    }
+}
+
+/// Result types of a query, as returned by `DESCRIBE (query)`.
+async fn describe_query(
+    query: &str,
+    params: &Params,
+    cache: Option<&DescribeCache>,
+    client: &impl ClientGeneric,
+) -> Result<IndexMap<String, ClickhouseType>, Error> {
+    if let Some(types) = cache.and_then(|cache| cache.get(query, params)) {
+        return Ok(types);
+    }
+    debug!(query, "Describing query");
+    let subject = format!("({})", query.trim().trim_end_matches(';'));
+    let types = crate::table::describe(&subject, params, client).await?;
+    if let Some(cache) = cache {
+        cache.insert(query, params, types.clone());
+    }
+    Ok(types)
+}
+
+/// Replace the types of the columns matching an override.
+/// Nullability of the original type is preserved.
+fn apply_overrides(
+    types: &mut IndexMap<String, ClickhouseType>,
+    overrides: &[(ColumnPattern, ClickhouseType)],
+) {
+    for (col, type_) in types.iter_mut() {
+        if let Some((_, override_)) = overrides.iter().find(|(pattern, _)| pattern.matches(col)) {
+            *type_ = if type_.is_nullable() && !override_.is_nullable() {
+                override_.clone().nullable()
+            } else {
+                override_.clone()
+            };
         }
     }
 }
@@ -28,72 +184,363 @@ impl Default for GetOptions {
 ///
 /// The schema is inferred from the query for columns not present in the `types` argument, which can
 /// be used to correct e.g. booleans returned by Clickhouse as their internal [u8] representation.
-/// See also the [table_types_from_clickhouse](crate::table_types_from_clickhouse) method.
+/// See also the [ClickhouseTable::from_server](crate::ClickhouseTable::from_server) method and the
+/// `describe` option.
 pub async fn get_df_query<C: ClientGeneric>(
     query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
     options: GetOptions,
     client: &C,
 ) -> Result<DataFrame, Error> {
-    debug!("Retrieving data from Clickhouse",);
+    let rechunk = options.rechunk;
+    let mut stream = std::pin::pin!(get_df_stream(query, options, client).await?);
+    // The blocks are appended as chunks, without copying.
+    let mut df: Option<DataFrame> = None;
+    while let Some(batch) = stream.try_next().await? {
+        match &mut df {
+            Some(df) => {
+                df.vstack_mut(&batch)?;
+            }
+            None => df = Some(batch),
+        }
+    }
+    let mut df = df.unwrap_or_default();
+    if rechunk {
+        df.as_single_chunk_par();
+    }
+    Ok(df)
+}
+
+/// Retrieve Clickhouse query results as a stream of [DataFrame], one per received block (or per
+/// [GetOptions::batch_rows] rows), so that large results can be processed incrementally.
+///
+/// All dataframes have the same schema. See [get_df_query] for the type inference.
+///
+/// Dropping the stream before its end abandons the query, which is also stopped on the server:
+/// the HTTP client kills it, and native connections send the Cancel packet of the protocol.
+pub async fn get_df_stream<C: ClientGeneric>(
+    query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+    options: GetOptions,
+    client: &C,
+) -> Result<impl Stream<Item = Result<DataFrame, Error>> + '_, Error> {
+    let Some(mut stop) = stop_signal(&options) else {
+        return Ok(open_df_stream(query, options, client).await?.left_stream());
+    };
+    let stream = tokio::select! {
+        e = &mut stop => return Err(e),
+        stream = open_df_stream(query, options, client) => stream?,
+    };
+    Ok(until_stopped(stream, stop).right_stream())
+}
 
-    let mut resp = client.query_raw(query).await?;
-    let initial = match resp.next().await {
-        Some(initial) => initial?,
-        None if !client.sends_initial_block() => {
-            return Ok(DataFrame::default());
+/// Future resolving to the reason for abandoning a query.
+type Stop = Pin<Box<dyn Future<Output = Error> + Send>>;
+
+/// Resolves when the query is cancelled or times out, if either option is set.
+fn stop_signal(options: &GetOptions) -> Option<Stop> {
+    if options.cancel.is_none() && options.timeout.is_none() {
+        return None;
+    }
+    let cancel = options.cancel.clone();
+    let timeout = options.timeout;
+    Some(Box::pin(async move {
+        let cancelled = async {
+            match &cancel {
+                Some(cancel) => cancel.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        let timed_out = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = cancelled => Error::Cancelled,
+            _ = timed_out => Error::Timeout(timeout.unwrap_or_default()),
         }
-        _ => {
-            return Err(
-                klickhouse::KlickhouseError::ProtocolError("Missing initial block".into()).into(),
-            );
+    }))
+}
+
+/// End the stream with the error of the stop signal once it resolves. The underlying stream is
+/// then dropped, which abandons the query.
+fn until_stopped<'a, T: 'a>(
+    stream: impl Stream<Item = Result<T, Error>> + 'a,
+    stop: Stop,
+) -> impl Stream<Item = Result<T, Error>> + 'a {
+    stream::unfold(Some((Box::pin(stream), stop)), |state| async move {
+        let (mut stream, mut stop) = state?;
+        tokio::select! {
+            e = &mut stop => Some((Err(e), None)),
+            next = stream.next() => next.map(|next| (next, Some((stream, stop)))),
         }
+    })
+}
+
+async fn open_df_stream<C: ClientGeneric>(
+    query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+    options: GetOptions,
+    client: &C,
+) -> Result<impl Stream<Item = Result<DataFrame, Error>> + '_, Error> {
+    debug!("Retrieving data from Clickhouse",);
+
+    let query = query.try_into()?.to_string();
+    let columnar = options.columnar && client.supports_columnar();
+    // `DateTime` and `Date` columns cannot be told apart from integers in Arrow streams.
+    let arrow = columnar && client.columnar_format() == ColumnarFormat::ArrowStream;
+    let described = if options.describe || arrow {
+        Some(
+            describe_query(
+                &query,
+                &options.params,
+                options.describe_cache.as_ref(),
+                client,
+            )
+            .await?,
+        )
+    } else {
+        None
     };
-    debug!(?initial, "Received initial block");
-    let mut ch_types: IndexMap<String, ClickhouseType> = initial
-        .column_types
-        .into_iter()
-        .map(|(col, type_)| -> Result<_, Error> { Ok((col, ClickhouseType::from(type_))) })
-        .try_collect()?;
+    let batch_rows = options.batch_rows;
+    let mut settings = options.settings.clone();
+    if let Some(timeout) = options.timeout {
+        settings
+            .entry("max_execution_time".into())
+            .or_insert_with(|| timeout.as_secs_f64().ceil().into());
+    }
+    let query_options = QueryOptions {
+        settings,
+        params: options.params.clone(),
+        progress: options.progress.clone(),
+        query_id: options.query_id.clone(),
+    };
+
+    let stream = if columnar {
+        let resp = client.query_columnar(query, query_options).await?;
+        columnar_dfs(resp, described, options).left_stream()
+    } else {
+        let mut resp = client.query_raw(query, query_options).await?;
+        let ch_types: IndexMap<String, ClickhouseType> = match resp.next().await {
+            Some(initial) => {
+                let initial = initial?;
+                debug!(?initial, "Received initial block");
+                initial
+                    .column_types
+                    .into_iter()
+                    .map(|(col, type_)| (col, ClickhouseType::from(type_)))
+                    .collect()
+            }
+            // Empty result
+            None if !client.sends_initial_block() => Default::default(),
+            _ => {
+                return Err(klickhouse::KlickhouseError::ProtocolError(
+                    "Missing initial block".into(),
+                )
+                .into());
+            }
+        };
+        let ch_types = resolve_types(ch_types, described, &options);
+        value_dfs(resp, ch_types, options).right_stream()
+    };
+
+    Ok(match batch_rows {
+        Some(rows) => rebatch(stream, rows).left_stream(),
+        None => stream.right_stream(),
+    })
+}
+
+/// Refine the types returned by the server with the `DESCRIBE` output and the [GetOptions].
+fn resolve_types(
+    mut ch_types: IndexMap<String, ClickhouseType>,
+    described: Option<IndexMap<String, ClickhouseType>>,
+    options: &GetOptions,
+) -> IndexMap<String, ClickhouseType> {
+    for (col, type_) in described.into_iter().flatten() {
+        if let Some(t) = ch_types.get_mut(&col) {
+            *t = type_;
+        }
+    }
+    apply_overrides(&mut ch_types, &options.overrides);
     ch_types.extend(options.types.clone());
+    ch_types
+}
 
-    get_df_stream(resp, ch_types, options).await
+/// Number of blocks converted concurrently, while the next ones are being received.
+const PREFETCH_BLOCKS: usize = 2;
+
+/// Convert the blocks on blocking threads, so that the next blocks are received from the network
+/// while the previous ones are being converted.
+fn convert_blocks<'a, B: Send + 'static>(
+    resp: impl Stream<Item = Result<B, Error>> + 'a,
+    convert: impl Fn(B) -> Result<DataFrame, Error> + Send + Sync + 'static,
+) -> impl Stream<Item = Result<DataFrame, Error>> + 'a {
+    let convert = Arc::new(convert);
+    resp.map(move |block| {
+        let convert = convert.clone();
+        async move {
+            let block = block?;
+            tokio::task::spawn_blocking(move || convert(block)).await?
+        }
+    })
+    .buffered(PREFETCH_BLOCKS)
 }
 
-async fn get_df_stream(
-    resp: impl Stream<Item = Result<klickhouse::block::Block, Error>>,
+/// Convert the columns of a block in parallel.
+fn convert_columns<T: Send>(
+    columns: IndexMap<String, T>,
+    ch_types: &IndexMap<String, ClickhouseType>,
+    convert: impl Fn(T, &ClickhouseType) -> Result<Series, Error> + Send + Sync,
+) -> Result<IndexMap<String, Series>, Error> {
+    let series: Vec<(String, Series)> = columns
+        .into_iter()
+        .collect_vec()
+        .into_par_iter()
+        .map(|(col, values)| {
+            let type_ = ch_types
+                .get(&col)
+                .ok_or_else(|| Error::MissingColumnLocal(col.clone()))?;
+            let mut series = convert(values, type_)?;
+            series.rename(&col);
+            Ok((col, series))
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(series.into_iter().collect())
+}
+
+/// Convert blocks of [klickhouse::Value] into dataframes.
+fn value_dfs<'a>(
+    resp: impl Stream<Item = Result<klickhouse::block::Block, Error>> + 'a,
     ch_types: IndexMap<String, ClickhouseType>,
     options: GetOptions,
-) -> Result<DataFrame, Error> {
-    debug!(?ch_types, "Building dataframe from stream");
-    let mut series: IndexMap<String, Series> = ch_types
-        .iter()
-        .map(|(col, type_)| -> Result<_, Error> {
-            let type_ = DataType::try_from(type_)?;
-            let series = Series::new_empty(col, &type_);
-            Ok((col.clone(), series))
-        })
-        .try_collect()?;
-    resp.and_then(|block| {
-        futures::future::ready(
-            block
-                .column_data
+) -> impl Stream<Item = Result<DataFrame, Error>> + 'a {
+    debug!(?ch_types, "Building dataframes from stream");
+    let resp = resp.try_filter(|block| futures::future::ready(block.rows > 0));
+    convert_blocks(resp, move |block| {
+        if !options.validate_values {
+            for (col, header) in &block.column_types {
+                if let Some(type_) = ch_types.get(col) {
+                    check_column_type(header, type_)?;
+                }
+            }
+        }
+        let series = convert_columns(block.column_data, &ch_types, |values, type_| {
+            values_to_series(values, type_.clone(), options.validate_values)
+        })?;
+        build_df(series, &options)
+    })
+}
+
+/// Convert blocks decoded by the columnar codec into dataframes.
+fn columnar_dfs<'a>(
+    resp: impl Stream<Item = Result<SeriesBlock, Error>> + 'a,
+    described: Option<IndexMap<String, ClickhouseType>>,
+    options: GetOptions,
+) -> impl Stream<Item = Result<DataFrame, Error>> + 'a {
+    let ch_types = OnceLock::new();
+    let resp = resp.try_filter(|block| futures::future::ready(block.rows > 0));
+    convert_blocks(resp, move |block| {
+        let ch_types = ch_types.get_or_init(|| {
+            let ch_types = resolve_types(block.column_types.clone(), described.clone(), &options);
+            debug!(?ch_types, "Building dataframes from columnar stream");
+            ch_types
+        });
+        let series = convert_columns(block.column_data, ch_types, convert_series)?;
+        build_df(series, &options)
+    })
+}
+
+/// Regroup dataframes into batches of `rows` rows (except for the last one).
+fn rebatch<'a>(
+    stream: impl Stream<Item = Result<DataFrame, Error>> + 'a,
+    rows: usize,
+) -> impl Stream<Item = Result<DataFrame, Error>> + 'a {
+    futures::stream::unfold(
+        (Box::pin(stream), DataFrame::default(), false),
+        move |(mut stream, mut buffer, mut done)| async move {
+            while !done && buffer.height() < rows {
+                match stream.next().await {
+                    Some(Ok(df)) if buffer.width() == 0 => buffer = df,
+                    Some(Ok(df)) => {
+                        if let Err(e) = buffer.vstack_mut(&df) {
+                            return Some((Err(e.into()), (stream, Default::default(), true)));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (stream, Default::default(), true))),
+                    None => done = true,
+                }
+            }
+            if buffer.height() == 0 {
+                return None;
+            }
+            let batch = buffer.slice(0, rows);
+            let rest = buffer.slice(rows as i64, buffer.height());
+            Some((Ok(batch), (stream, rest, done)))
+        },
+    )
+}
+
+/// Convert a series decoded by the columnar codec into the representation of the requested type.
+///
+/// Besides casts, this handles the representations of the Arrow output format of Clickhouse:
+/// `DateTime` as `UInt32` seconds, `Date` as `UInt16` days, and UUIDs as 16 bytes.
+fn convert_series(series: Series, type_: &ClickhouseType) -> Result<Series, Error> {
+    let dtype = DataType::try_from(type_)?;
+    if series.dtype() == &dtype {
+        return Ok(series);
+    }
+    let mismatch = || Error::MismatchingSeriesType(series.dtype().clone());
+    let mut out = match (
+        value_type(&klickhouse::Type::from(type_.clone())),
+        series.dtype(),
+    ) {
+        (klickhouse::Type::DateTime(tz), dtype) if dtype.is_integer() => {
+            let seconds = series.strict_cast(&DataType::Int64)?;
+            (seconds.i64()? * 1000)
+                .into_datetime(TimeUnit::Milliseconds, Some(tz.name().into()))
+                .into_series()
+        }
+        (klickhouse::Type::Date, dtype) if dtype.is_integer() => {
+            series.strict_cast(&DataType::Int32)?
+        }
+        (klickhouse::Type::Uuid, DataType::String) => {
+            let values: Vec<Option<Vec<u8>>> = series
+                .str()?
                 .into_iter()
-                .map(|(col, values)| {
-                    let series = series
-                        .get_mut(&col)
-                        .ok_or_else(|| Error::MissingColumnLocal(col.clone()))?;
-                    series.extend(&values_to_series(
-                        values,
-                        ch_types.get(&col).unwrap().clone(),
-                    )?)?;
-                    Ok(())
+                .map(|x| {
+                    x.map(|x| klickhouse::Uuid::parse_str(x).map(|x| x.as_bytes().to_vec()))
+                        .transpose()
                 })
-                .collect::<Result<Vec<_>, Error>>(),
-        )
-    })
-    .try_collect::<Vec<_>>()
-    .await?;
+                .collect::<Result<_, _>>()
+                .map_err(|_| mismatch())?;
+            Series::new("", values)
+        }
+        (klickhouse::Type::Uuid, DataType::Binary) => {
+            let values: Vec<Option<String>> = series
+                .binary()?
+                .into_iter()
+                .map(|x| {
+                    x.map(|x| klickhouse::Uuid::from_slice(x).map(|x| x.to_string()))
+                        .transpose()
+                })
+                .collect::<Result<_, _>>()
+                .map_err(|_| mismatch())?;
+            Series::new("", values)
+        }
+        _ => series.clone(),
+    };
+    out.rename(series.name());
+    // Casts between datetimes drop the time zone without the `timezones` feature of polars.
+    if out.dtype() == &dtype {
+        return Ok(out);
+    }
+    Ok(out.strict_cast(&dtype)?)
+}
 
+/// Assemble the series of a block into a [DataFrame].
+fn build_df(
+    mut series: IndexMap<String, Series>,
+    options: &GetOptions,
+) -> Result<DataFrame, Error> {
     // Remove 0-length series that were present in the `ch_types` but not returned.
     series.retain(|_, vals| !vals.is_empty());
 
@@ -132,6 +579,19 @@ impl TryFrom<&ClickhouseType> for DataType {
             ClickhouseType::Bool => DataType::Boolean,
 
             ClickhouseType::Native(klickhouse::Type::Uuid) => DataType::String,
+            ClickhouseType::BinaryUuid => DataType::Binary,
+
+            ClickhouseType::Native(klickhouse::Type::DateTime(tz)) => {
+                DataType::Datetime(TimeUnit::Milliseconds, Some(tz.name().into()))
+            }
+            ClickhouseType::Native(klickhouse::Type::Date) => DataType::Date,
+
+            // Decimals are represented as floats, as on insertion.
+            ClickhouseType::Native(
+                klickhouse::Type::Decimal32(_)
+                | klickhouse::Type::Decimal64(_)
+                | klickhouse::Type::Decimal128(_),
+            ) => DataType::Float64,
 
             // Lists
             ClickhouseType::Native(klickhouse::Type::Array(inner)) => {
@@ -139,9 +599,9 @@ impl TryFrom<&ClickhouseType> for DataType {
                 DataType::List(Box::new(DataType::try_from(&inner)?))
             }
 
-            // Categoricals
+            // Categoricals, which are nullable
             ClickhouseType::Native(klickhouse::Type::LowCardinality(s))
-                if s == &Box::new(klickhouse::Type::String) =>
+                if s.strip_null() == &klickhouse::Type::String =>
             {
                 DataType::Categorical(None, CategoricalOrdering::Physical)
             }
@@ -175,21 +635,54 @@ macro_rules! extract {
             .collect()
     }};
 }
+/// Type of the non-null values of a column, up to nullability and low cardinality.
+fn value_type(type_: &klickhouse::Type) -> klickhouse::Type {
+    type_
+        .strip_null()
+        .strip_low_cardinality()
+        .strip_null()
+        .clone()
+}
+
+/// Whether values of the given types have the same representation. `DateTime` values are
+/// timestamps, whose time zone only affects their display and can therefore be overridden.
+fn same_value_type(a: &klickhouse::Type, b: &klickhouse::Type) -> bool {
+    match (a, b) {
+        (klickhouse::Type::DateTime(_), klickhouse::Type::DateTime(_)) => true,
+        _ => a == b,
+    }
+}
+
+/// Check that the type of a column in a block header matches the expected type, so that the
+/// values do not need to be checked individually.
+fn check_column_type(header: &klickhouse::Type, type_: &ClickhouseType) -> Result<(), Error> {
+    let header = value_type(header);
+    let expected = value_type(&klickhouse::Type::from(type_.clone()));
+    if !same_value_type(&header, &expected) {
+        return Err(Error::MismatchingValueType(header, expected));
+    }
+    Ok(())
+}
+
+/// Convert values into a series of the given type.
+///
+/// With `validate`, the type of every value is checked. Otherwise, the values must already be
+/// known to have the expected type, e.g. with [check_column_type].
 pub(crate) fn values_to_series(
     values: Vec<klickhouse::Value>,
     type_: ClickhouseType,
+    validate: bool,
 ) -> Result<Series, Error> {
-    let type_k = klickhouse::Type::from(type_.clone())
-        .strip_null()
-        .strip_low_cardinality()
-        .clone();
-    for val in &values {
-        if val == &klickhouse::Value::Null {
-            continue;
-        }
-        let type_ = val.guess_type();
-        if type_ != type_k {
-            return Err(Error::MismatchingValueType(type_, type_k));
+    if validate {
+        let type_k = value_type(&klickhouse::Type::from(type_.clone()));
+        for val in &values {
+            if val == &klickhouse::Value::Null {
+                continue;
+            }
+            let type_ = val.guess_type();
+            if !same_value_type(&type_, &type_k) {
+                return Err(Error::MismatchingValueType(type_, type_k));
+            }
         }
     }
 
@@ -209,6 +702,47 @@ pub(crate) fn values_to_series(
             let vals: Vec<_> = extract!(values, Uuid, |val: klickhouse::Uuid| val.to_string());
             Series::new("", vals)
         }
+        ClickhouseType::BinaryUuid => {
+            let vals: Vec<Option<Vec<u8>>> = extract!(values, Uuid, |val: klickhouse::Uuid| val
+                .as_bytes()
+                .to_vec());
+            Series::new("", vals)
+        }
+
+        ClickhouseType::Native(klickhouse::Type::DateTime(tz)) => {
+            // Seconds since the epoch
+            let vals: Int64Chunked = extract!(values, DateTime, |val: klickhouse::DateTime| {
+                i64::from(val.1) * 1000
+            });
+            vals.into_datetime(TimeUnit::Milliseconds, Some(tz.name().into()))
+                .into_series()
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Date) => {
+            // Days since the epoch
+            let vals: Int32Chunked =
+                extract!(values, Date, |val: klickhouse::Date| i32::from(val.0));
+            vals.into_date().into_series()
+        }
+
+        ClickhouseType::Native(
+            klickhouse::Type::Decimal32(scale)
+            | klickhouse::Type::Decimal64(scale)
+            | klickhouse::Type::Decimal128(scale),
+        ) => {
+            let factor = 10f64.powi(scale as i32);
+            let vals: Float64Chunked = values
+                .into_iter()
+                .map(|val| match val {
+                    klickhouse::Value::Decimal32(_, x) => Some(f64::from(x) / factor),
+                    klickhouse::Value::Decimal64(_, x) => Some(x as f64 / factor),
+                    klickhouse::Value::Decimal128(_, x) => Some(x as f64 / factor),
+                    klickhouse::Value::Null => None,
+                    _ => unreachable!("expected Decimal, got {:?}", val),
+                })
+                .collect();
+            vals.into_series()
+        }
 
         ClickhouseType::Native(klickhouse::Type::UInt8) => extract!(values, UInt8),
         ClickhouseType::Native(klickhouse::Type::UInt16) => extract!(values, UInt16),
@@ -224,15 +758,15 @@ pub(crate) fn values_to_series(
         ClickhouseType::Native(klickhouse::Type::Float64) => extract!(values, Float64),
 
         ClickhouseType::Native(klickhouse::Type::LowCardinality(s))
-            if *s == klickhouse::Type::String =>
+            if s.strip_null() == &klickhouse::Type::String =>
         {
             extract_string(values).cast(&DataType::Categorical(None, Default::default()))?
         }
 
         // Nulls
-        ClickhouseType::Nullable(type_) => values_to_series(values, *type_)?,
+        ClickhouseType::Nullable(type_) => values_to_series(values, *type_, false)?,
         ClickhouseType::Native(klickhouse::Type::Nullable(inner)) => {
-            values_to_series(values, ClickhouseType::from(*inner))?
+            values_to_series(values, ClickhouseType::from(*inner), false)?
         }
 
         ClickhouseType::Native(klickhouse::Type::Array(inner)) => {
@@ -240,7 +774,7 @@ pub(crate) fn values_to_series(
             let series: Vec<Series> = values
                 .into_iter()
                 .map(move |val| match val {
-                    klickhouse::Value::Array(val) => values_to_series(val, inner.clone()),
+                    klickhouse::Value::Array(val) => values_to_series(val, inner.clone(), validate),
                     klickhouse::Value::Null => Err(Error::UnexpectedNull("In array")),
                     _ => Err(Error::UnsupportedClickhouseType(ClickhouseType::Native(
                         val.guess_type(),
@@ -255,3 +789,145 @@ pub(crate) fn values_to_series(
     };
     Ok(series)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn overrides() -> anyhow::Result<()> {
+        let mut types: IndexMap<String, ClickhouseType> = [
+            ("is_rich", "UInt8"),
+            ("is_hero", "Nullable(UInt8)"),
+            ("hero_id", "UUID"),
+            ("name", "String"),
+        ]
+        .into_iter()
+        .map(|(col, type_)| Ok((col.to_string(), type_.parse::<ClickhouseType>()?)))
+        .collect::<Result<_, Error>>()?;
+        apply_overrides(
+            &mut types,
+            &[
+                (ColumnPattern::glob("is_*"), ClickhouseType::Bool),
+                (ColumnPattern::glob("*_id"), ClickhouseType::BinaryUuid),
+                (ColumnPattern::Exact("name".into()), ClickhouseType::Json),
+                (ColumnPattern::Exact("name".into()), ClickhouseType::Bool),
+            ],
+        );
+        assert_eq!(types["is_rich"], ClickhouseType::Bool);
+        assert_eq!(types["is_hero"], ClickhouseType::Bool.nullable());
+        assert_eq!(types["hero_id"], ClickhouseType::BinaryUuid);
+        assert_eq!(types["name"], ClickhouseType::Json);
+
+        assert!(!ColumnPattern::glob("is_*").matches("this_is_rich"));
+        assert!(ColumnPattern::glob("a.b*").matches("a.b.c"));
+        assert!(!ColumnPattern::glob("a.b*").matches("axb"));
+        Ok(())
+    }
+    #[test]
+    fn convert_arrow() -> anyhow::Result<()> {
+        let datetime = convert_series(
+            Series::new("a", &[Some(1_700_000_000u32), None]),
+            &"Nullable(DateTime('Europe/Paris'))".parse()?,
+        )?;
+        assert_eq!(datetime.name(), "a");
+        assert_eq!(
+            datetime.dtype(),
+            &DataType::Datetime(TimeUnit::Milliseconds, Some("Europe/Paris".into()))
+        );
+        assert_eq!(datetime.datetime()?.get(0), Some(1_700_000_000_000));
+        assert_eq!(datetime.null_count(), 1);
+
+        let date = convert_series(Series::new("b", &[19_000u16]), &"Date".parse()?)?;
+        assert_eq!(date.dtype(), &DataType::Date);
+        assert_eq!(date.date()?.get(0), Some(19_000));
+
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let bytes = klickhouse::Uuid::parse_str(uuid)?.as_bytes().to_vec();
+        let series = convert_series(Series::new("c", &[bytes.as_slice()]), &"UUID".parse()?)?;
+        assert_eq!(series.str()?.get(0), Some(uuid));
+        Ok(())
+    }
+    #[test]
+    fn describe_cache() -> anyhow::Result<()> {
+        let cache = DescribeCache::new(2);
+        let types: IndexMap<String, ClickhouseType> = [("a".to_string(), ClickhouseType::Bool)]
+            .into_iter()
+            .collect();
+        let params: Params = [("x".to_string(), 1.into())].into_iter().collect();
+        cache.insert("SELECT 1", &Default::default(), types.clone());
+        cache.insert("SELECT 1", &params, types.clone());
+        assert_eq!(cache.get("SELECT 1", &params), Some(types.clone()));
+        assert!(cache
+            .get(
+                "SELECT 1",
+                &[("x".to_string(), 2.into())].into_iter().collect()
+            )
+            .is_none());
+        // The oldest entry is evicted.
+        cache.clone().insert("SELECT 2", &params, types.clone());
+        assert!(cache.get("SELECT 1", &Default::default()).is_none());
+        assert!(cache.get("SELECT 2", &params).is_some());
+        cache.clear();
+        assert!(cache.get("SELECT 2", &params).is_none());
+        Ok(())
+    }
+    #[test]
+    fn column_types() -> anyhow::Result<()> {
+        let bool_ = ClickhouseType::Bool.nullable();
+        check_column_type(&"Nullable(UInt8)".parse::<klickhouse::Type>()?, &bool_)?;
+        check_column_type(
+            &"LowCardinality(Nullable(String))".parse::<klickhouse::Type>()?,
+            &"String".parse::<ClickhouseType>()?,
+        )?;
+        assert!(check_column_type(&klickhouse::Type::Int32, &bool_).is_err());
+
+        let values = vec![
+            klickhouse::Value::UInt8(1),
+            klickhouse::Value::Null,
+            klickhouse::Value::UInt8(0),
+        ];
+        let series = values_to_series(values.clone(), bool_.clone(), true)?;
+        assert_eq!(series.bool()?.get(0), Some(true));
+        assert_eq!(series.null_count(), 1);
+        assert!(values_to_series(values, bool_.clone(), false)?.equals_missing(&series));
+        assert!(values_to_series(vec![klickhouse::Value::Int32(1)], bool_, true).is_err());
+
+        let categorical: ClickhouseType = "LowCardinality(Nullable(String))".parse()?;
+        assert!(matches!(
+            DataType::try_from(&categorical)?,
+            DataType::Categorical(..)
+        ));
+        let values = vec![
+            klickhouse::Value::String("x".into()),
+            klickhouse::Value::Null,
+        ];
+        let series = values_to_series(values, categorical, true)?;
+        assert!(matches!(series.dtype(), DataType::Categorical(..)));
+        assert_eq!(series.null_count(), 1);
+        Ok(())
+    }
+    #[test]
+    fn datetime_override() -> anyhow::Result<()> {
+        // The server sends the time zone of the column, or its own.
+        let header: klickhouse::Type = "DateTime('UTC')".parse()?;
+        let klickhouse::Type::DateTime(utc) = header else {
+            unreachable!()
+        };
+        let paris: ClickhouseType = "Nullable(DateTime('Europe/Paris'))".parse()?;
+        check_column_type(&header, &paris)?;
+        assert!(check_column_type(&header, &ClickhouseType::Bool).is_err());
+
+        let values = vec![
+            klickhouse::Value::DateTime(klickhouse::DateTime(utc, 1_700_000_000)),
+            klickhouse::Value::Null,
+        ];
+        let series = values_to_series(values, paris.clone(), true)?;
+        assert_eq!(series.dtype(), &DataType::try_from(&paris)?);
+        assert_eq!(
+            series.datetime()?.get(0),
+            Some(1_700_000_000_000),
+            "The timestamp is preserved"
+        );
+        Ok(())
+    }
+}
@@ -1,64 +1,187 @@
-use futures::{stream, Stream, StreamExt, TryStreamExt};
+use futures::{stream, stream::BoxStream, Stream, StreamExt, TryStreamExt};
 use klickhouse::block::Block;
 use tokio::io::AsyncBufReadExt;
+use tracing::*;
 
-use crate::{Error, HttpError};
+use crate::{
+    native,
+    query::{add_settings_clause, ProfileInfo, Progress, QueryOptions, Settings},
+    retry::{is_read_query, RetryPolicy},
+    Error, HttpError, SeriesBlock, ServerException,
+};
 
+/// Compression of the data exchanged with the server.
+///
+/// With the native protocol, klickhouse selects the compression at build time: the
+/// `native-compression` feature enables LZ4 for all native connections, which must then use
+/// [Compression::Lz4], and [Compression::None] is only available without it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 compression of the blocks, with the native protocol.
+    Lz4,
+    /// Zstandard compression of the responses and of the inserted data, with the HTTP interface.
+    Zstd,
+}
+
+/// Format of the blocks exchanged by [ClientGeneric::query_columnar] and
+/// [ClientGeneric::insert_columnar].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColumnarFormat {
+    #[default]
+    Native,
+    /// Arrow IPC streams, decoded directly into series. As `DateTime` and `Date` columns are sent
+    /// as `UInt32` and `UInt16`, the types of the columns are resolved with `DESCRIBE (query)`
+    /// before each query (see [GetOptions::describe](crate::GetOptions::describe)).
+    ArrowStream,
+}
+impl ColumnarFormat {
+    /// Name of the format in Clickhouse queries.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColumnarFormat::Native => "Native",
+            ColumnarFormat::ArrowStream => "ArrowStream",
+        }
+    }
+}
+
+/// Client for the native protocol or the HTTP interface, with default settings for all queries.
+#[derive(Clone)]
+pub struct Client {
+    inner: ClientKind,
+    /// Settings applied to all queries, unless overridden by [QueryOptions::settings].
+    pub settings: Settings,
+    /// Retries of read queries, disabled by default.
+    pub retry: Option<RetryPolicy>,
+}
 #[derive(Clone)]
-pub enum Client {
+enum ClientKind {
     Native(klickhouse::Client),
     Http(http::HttpClient),
 }
 impl Client {
+    /// Connect to the HTTP interface for `http://` and `https://` addresses, and to the native
+    /// protocol otherwise.
+    ///
+    /// Fails with [Error::UnsupportedCompression] if the compression is not available for the
+    /// interface, see [Compression]. Further HTTP options (timeouts, TLS, proxies, headers) are set
+    /// with [Client::connect_http].
     pub async fn connect(
         address: &str,
         default_database: Option<&str>,
         username: &str,
         password: Option<&str>,
+        compression: Compression,
     ) -> Result<Self, Error> {
         if address.starts_with("http://") || address.starts_with("https://") {
-            Ok(Self::Http(http::HttpClient::new(
-                address,
-                default_database,
-                username,
-                password,
-            )))
+            let mut builder = http::HttpClientBuilder::new(address)
+                .credentials(username, password)
+                .compression(compression);
+            if let Some(database) = default_database {
+                builder = builder.database(database);
+            }
+            Self::connect_http(builder)
         } else {
-            klickhouse::Client::connect(
-                address,
-                klickhouse::ClientOptions {
-                    username: username.into(),
-                    password: password.unwrap_or_default().into(),
-                    default_database: default_database.unwrap_or("default").into(),
-                },
-            )
-            .await
-            .map(Self::Native)
-            .map_err(Error::from)
+            // Klickhouse selects the compression when it is built.
+            let available = if cfg!(feature = "native-compression") {
+                Compression::Lz4
+            } else {
+                Compression::None
+            };
+            if compression != available {
+                return Err(Error::UnsupportedCompression(compression));
+            }
+            let options = klickhouse::ClientOptions {
+                username: username.into(),
+                password: password.unwrap_or_default().into(),
+                default_database: default_database.unwrap_or("default").into(),
+            };
+            Ok(klickhouse::Client::connect(address, options).await?.into())
+        }
+    }
+    /// Client for the HTTP interface, with the options of the builder (timeouts, TLS, proxies,
+    /// headers, sessions).
+    pub fn connect_http(builder: http::HttpClientBuilder) -> Result<Self, Error> {
+        Ok(builder.build()?.into())
+    }
+    /// Set default settings for all queries.
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+    /// Retry read queries failing with transient errors.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+    /// Run the attempts with the retry policy if the query only reads data, with the options of
+    /// each attempt (see [QueryOptions::query_id]).
+    ///
+    /// The first block is awaited in each attempt, as the native protocol returns server
+    /// exceptions in the stream.
+    async fn with_retries<T: Send + 'static, F>(
+        &self,
+        query: &str,
+        options: QueryOptions,
+        attempt: impl Fn(QueryOptions) -> F,
+    ) -> Result<BoxStream<'static, Result<T, Error>>, Error>
+    where
+        F: std::future::Future<Output = Result<BoxStream<'static, Result<T, Error>>, Error>>,
+    {
+        let peeked = |number| {
+            let stream = attempt(options.for_attempt(number));
+            async {
+                let mut stream = stream.await?;
+                match stream.next().await {
+                    Some(Err(e)) => Err(e),
+                    first => Ok(stream::iter(first).chain(stream).boxed()),
+                }
+            }
+        };
+        match &self.retry {
+            Some(retry) if is_read_query(query) => retry.run(peeked).await,
+            _ => attempt(options).await,
+        }
+    }
+}
+impl From<http::HttpClient> for Client {
+    fn from(client: http::HttpClient) -> Self {
+        Self {
+            inner: ClientKind::Http(client),
+            settings: Default::default(),
+            retry: None,
+        }
+    }
+}
+impl From<klickhouse::Client> for Client {
+    fn from(client: klickhouse::Client) -> Self {
+        Self {
+            inner: ClientKind::Native(client),
+            settings: Default::default(),
+            retry: None,
         }
     }
 }
 // Manual dynamic dispatch boilerplace because the trait is not object-safe
 impl ClientGeneric for Client {
     fn sends_initial_block(&self) -> bool {
-        match self {
-            Client::Native(c) => c.sends_initial_block(),
-            Client::Http(c) => c.sends_initial_block(),
+        match &self.inner {
+            ClientKind::Native(c) => c.sends_initial_block(),
+            ClientKind::Http(c) => c.sends_initial_block(),
         }
     }
     async fn insert_native_raw(
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
         blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
-    ) -> Result<impl Stream<Item = Result<Block, Error>>, Error> {
-        match self {
-            Client::Native(c) => Ok(c
-                .insert_native_raw(query, blocks)
-                .await?
-                .map_err(Error::from)
-                .boxed()),
-            Client::Http(c) => Ok(c
-                .insert_native_raw(query, blocks)
+        options: QueryOptions,
+    ) -> Result<impl Stream<Item = Result<Block, Error>> + 'static, Error> {
+        let options = options.with_default_settings(&self.settings);
+        match &self.inner {
+            ClientKind::Native(c) => native_insert_raw(c, query, blocks, options).await,
+            ClientKind::Http(c) => Ok(c
+                .insert_native_raw(query, blocks, options)
                 .await?
                 .map_err(Error::from)
                 .boxed()),
@@ -67,10 +190,68 @@ impl ClientGeneric for Client {
     async fn query_raw(
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
-    ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin, Error> {
-        match self {
-            Client::Native(c) => Ok(c.query_raw(query).await?.map_err(Error::from).boxed()),
-            Client::Http(c) => Ok(c.query_raw(query).await?.map_err(Error::from).boxed()),
+        options: QueryOptions,
+    ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin + 'static, Error> {
+        let query = query.try_into()?.to_string();
+        let options = options.with_default_settings(&self.settings);
+        self.with_retries(&query, options, |options| {
+            let query = query.clone();
+            async move {
+                match &self.inner {
+                    ClientKind::Native(c) => native_query_raw(c, query, options).await,
+                    ClientKind::Http(c) => Ok(c
+                        .query_raw(query, options)
+                        .await?
+                        .map_err(Error::from)
+                        .boxed()),
+                }
+            }
+        })
+        .await
+    }
+    fn supports_columnar(&self) -> bool {
+        match &self.inner {
+            ClientKind::Native(c) => c.supports_columnar(),
+            ClientKind::Http(c) => c.supports_columnar(),
+        }
+    }
+    fn columnar_format(&self) -> ColumnarFormat {
+        match &self.inner {
+            ClientKind::Native(c) => c.columnar_format(),
+            ClientKind::Http(c) => c.columnar_format(),
+        }
+    }
+    async fn query_columnar(
+        &self,
+        query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        options: QueryOptions,
+    ) -> Result<BoxStream<'static, Result<SeriesBlock, Error>>, Error> {
+        let query = query.try_into()?.to_string();
+        let options = options.with_default_settings(&self.settings);
+        self.with_retries(&query, options, |options| {
+            let query = query.clone();
+            async move {
+                match &self.inner {
+                    ClientKind::Native(c) => c.query_columnar(query, options).await,
+                    ClientKind::Http(c) => c.query_columnar(query, options).await,
+                }
+            }
+        })
+        .await
+    }
+    fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+    async fn insert_columnar(
+        &self,
+        query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        blocks: impl Stream<Item = Vec<u8>> + Send + Sync + Unpin + 'static,
+        options: QueryOptions,
+    ) -> Result<(), Error> {
+        let options = options.with_default_settings(&self.settings);
+        match &self.inner {
+            ClientKind::Native(c) => c.insert_columnar(query, blocks, options).await,
+            ClientKind::Http(c) => c.insert_columnar(query, blocks, options).await,
         }
     }
 }
@@ -81,18 +262,68 @@ pub trait ClientGeneric {
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
         blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
-    ) -> impl std::future::Future<Output = Result<impl Stream<Item = Result<Block, Error>>, Error>>;
+        options: QueryOptions,
+    ) -> impl std::future::Future<
+        Output = Result<impl Stream<Item = Result<Block, Error>> + 'static, Error>,
+    >;
     fn query_raw(
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
-    ) -> impl std::future::Future<Output = Result<impl Stream<Item = Result<Block, Error>> + Unpin, Error>>;
+        options: QueryOptions,
+    ) -> impl std::future::Future<
+        Output = Result<impl Stream<Item = Result<Block, Error>> + Unpin + 'static, Error>,
+    >;
+    /// Whether the client exchanges blocks in the Native format directly, in which case
+    /// [ClientGeneric::query_columnar] and [ClientGeneric::insert_columnar] are used rather than
+    /// the conversions through [klickhouse::Value].
+    ///
+    /// Only the HTTP client does: klickhouse decodes and encodes the blocks of the native protocol
+    /// itself, and does not expose their bytes.
+    fn supports_columnar(&self) -> bool {
+        false
+    }
+    fn columnar_format(&self) -> ColumnarFormat {
+        ColumnarFormat::Native
+    }
+    /// Retrieve query results as blocks of [polars::series::Series].
+    fn query_columnar(
+        &self,
+        _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        _options: QueryOptions,
+    ) -> impl std::future::Future<Output = Result<BoxStream<'static, Result<SeriesBlock, Error>>, Error>>
+    {
+        async { Err(Error::ColumnarUnsupported) }
+    }
+    /// Insert blocks encoded in the Native format.
+    fn insert_columnar(
+        &self,
+        _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        _blocks: impl Stream<Item = Vec<u8>> + Send + Sync + Unpin + 'static,
+        _options: QueryOptions,
+    ) -> impl std::future::Future<Output = Result<(), Error>> {
+        async { Err(Error::ColumnarUnsupported) }
+    }
+    /// Retries applied to the queries of the client, and to the inserts of
+    /// [ClickhouseTable::insert_df](crate::ClickhouseTable::insert_df) when they are idempotent.
+    fn retry_policy(&self) -> Option<&RetryPolicy> {
+        None
+    }
     fn execute(
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+    ) -> impl std::future::Future<Output = Result<(), Error>> {
+        self.execute_with(query, Default::default())
+    }
+    fn execute_with(
+        &self,
+        query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        options: QueryOptions,
     ) -> impl std::future::Future<Output = Result<(), Error>> {
         // From the implementation of klickhouse::Client::execute
         async {
-            let mut stream = self.query::<klickhouse::RawRow>(query).await?;
+            let mut stream = self
+                .query_with::<klickhouse::RawRow>(query, options)
+                .await?;
             while let Some(next) = stream.next().await {
                 next?;
             }
@@ -103,10 +334,18 @@ pub trait ClientGeneric {
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
     ) -> impl std::future::Future<Output = Result<impl Stream<Item = Result<T, Error>> + Unpin, Error>>
+    {
+        self.query_with(query, Default::default())
+    }
+    fn query_with<T: klickhouse::Row>(
+        &self,
+        query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+        options: QueryOptions,
+    ) -> impl std::future::Future<Output = Result<impl Stream<Item = Result<T, Error>> + Unpin, Error>>
     {
         // From the implementation of klickhouse::Client::query
         async {
-            let raw = self.query_raw(query).await?;
+            let raw = self.query_raw(query, options).await?;
             Ok(raw.flat_map(|block| match block {
                 Ok(mut block) => stream::iter(
                     block
@@ -120,6 +359,175 @@ pub trait ClientGeneric {
         }
     }
 }
+/// Aborts the task when dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Query sent on a native connection: forwards its progress and profile to the handler, and asks
+/// the server to cancel it when dropped before its results are complete.
+///
+/// The query is sent with its id, and its progress packets are recognized by it from the start,
+/// including those sent by inserts before their response.
+struct NativeQuery {
+    client: klickhouse::Client,
+    query_id: String,
+    forward: Option<ProgressForward>,
+    finished: bool,
+}
+/// Task forwarding the progress packets of a query to its handler.
+struct ProgressForward {
+    task: AbortOnDrop,
+    /// Signals the end of the query, after which the packets already received are forwarded.
+    done: tokio::sync::oneshot::Sender<()>,
+}
+impl NativeQuery {
+    /// Subscribe to the progress of the query, which must be sent afterwards with
+    /// [NativeQuery::options]. A random id is used if the options do not set one.
+    fn new(client: &klickhouse::Client, options: &QueryOptions) -> Self {
+        let query_id = options
+            .query_id
+            .clone()
+            .unwrap_or_else(|| klickhouse::Uuid::new_v4().to_string());
+        let forward = options.progress.clone().map(|handler| {
+            let (done, mut end) = tokio::sync::oneshot::channel();
+            let mut packets = client.subscribe_events();
+            let (id, start) = (query_id.clone(), std::time::Instant::now());
+            let mut progress = Progress::default();
+            let mut forward = move |(query_id, event)| {
+                if query_id != id {
+                    return;
+                }
+                match event {
+                    klickhouse::QueryEvent::Progress(packet) => {
+                        progress.add(&packet, start.elapsed())
+                    }
+                    klickhouse::QueryEvent::ProfileInfo(info) => {
+                        progress.profile = Some(ProfileInfo::from(&info))
+                    }
+                }
+                handler.report(progress);
+            };
+            let task = tokio::spawn(async move {
+                use tokio::sync::broadcast::error::RecvError;
+                loop {
+                    tokio::select! {
+                        packet = packets.recv() => match packet {
+                            Ok(packet) => forward(packet),
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => return,
+                        },
+                        _ = &mut end => break,
+                    }
+                }
+                // The packets of the query are all received before its end.
+                while let Ok(packet) = packets.try_recv() {
+                    forward(packet);
+                }
+            });
+            ProgressForward {
+                task: AbortOnDrop(task),
+                done,
+            }
+        });
+        Self {
+            client: client.clone(),
+            query_id,
+            forward,
+            finished: false,
+        }
+    }
+    /// Options sending the query with its id and parameters.
+    fn options(&self, options: &QueryOptions) -> klickhouse::QueryOptions {
+        klickhouse::QueryOptions {
+            query_id: Some(self.query_id.clone()),
+            parameters: options
+                .params
+                .iter()
+                .map(|(name, value)| (name.clone(), value.text()))
+                .collect(),
+        }
+    }
+    /// Track the query through the blocks of its response.
+    fn track(
+        self,
+        blocks: impl Stream<Item = Result<Block, klickhouse::KlickhouseError>> + Send + 'static,
+    ) -> BoxStream<'static, Result<Block, Error>> {
+        stream::unfold(
+            (Box::pin(blocks), self),
+            |(mut blocks, mut query)| async move {
+                let block = blocks.next().await;
+                // The stream ends after an exception.
+                if !matches!(block, Some(Ok(_))) {
+                    query.finish().await;
+                }
+                block.map(|block| (block.map_err(Error::from), (blocks, query)))
+            },
+        )
+        .boxed()
+    }
+    /// Mark the query as finished, once its progress is forwarded.
+    async fn finish(&mut self) {
+        self.finished = true;
+        if let Some(mut forward) = self.forward.take() {
+            forward.done.send(()).ok();
+            (&mut forward.task.0).await.ok();
+        }
+    }
+}
+impl Drop for NativeQuery {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (client, query_id) = (self.client.clone(), std::mem::take(&mut self.query_id));
+        debug!(query_id, "Cancelling abandoned query");
+        runtime.spawn(async move {
+            if let Err(e) = client.cancel(query_id).await {
+                warn!("Failed to cancel abandoned query: {}", e);
+            }
+        });
+    }
+}
+
+/// Send a query on a native connection, cancelling it when abandoned.
+async fn native_query_raw(
+    client: &klickhouse::Client,
+    query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+    options: QueryOptions,
+) -> Result<BoxStream<'static, Result<Block, Error>>, Error> {
+    let query = add_settings_clause(&query.try_into()?.to_string(), &options.settings)?;
+    let tracker = NativeQuery::new(client, &options);
+    let blocks = client
+        .query_raw_with(query, tracker.options(&options))
+        .await?;
+    Ok(tracker.track(blocks))
+}
+
+/// Send an insert on a native connection, see [native_query_raw].
+async fn native_insert_raw(
+    client: &klickhouse::Client,
+    query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+    blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
+    options: QueryOptions,
+) -> Result<BoxStream<'static, Result<Block, Error>>, Error> {
+    let query = add_settings_clause(&query.try_into()?.to_string(), &options.settings)?;
+    let tracker = NativeQuery::new(client, &options);
+    let response = client
+        .insert_native_raw_with(query, blocks, tracker.options(&options))
+        .await?;
+    Ok(tracker.track(response))
+}
+
+/// Settings are passed in a `SETTINGS` clause, as klickhouse does not support them in the
+/// protocol. Query parameters and query ids are sent in the protocol, and abandoned queries are
+/// cancelled with a Cancel packet.
 impl ClientGeneric for klickhouse::Client {
     fn sends_initial_block(&self) -> bool {
         true
@@ -128,57 +536,445 @@ impl ClientGeneric for klickhouse::Client {
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
         blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
-    ) -> Result<impl Stream<Item = Result<Block, Error>>, Error> {
-        Ok(self
-            .insert_native_raw(query, blocks)
-            .await?
-            .map_err(Error::from))
+        options: QueryOptions,
+    ) -> Result<impl Stream<Item = Result<Block, Error>> + 'static, Error> {
+        native_insert_raw(self, query, blocks, options).await
     }
     async fn query_raw(
         &self,
         query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
-    ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin, Error> {
-        Ok(self.query_raw(query).await?.map_err(Error::from))
+        options: QueryOptions,
+    ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin + 'static, Error> {
+        native_query_raw(self, query, options).await
     }
 }
 
 pub mod http {
 
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
     use super::*;
 
     /// Client for the Clickhouse HTTP interface, using the native format.
     pub struct HttpClient {
         builder: reqwest::RequestBuilder,
         database: String,
+        compression: Compression,
+        format: ColumnarFormat,
+        session: Option<Session>,
     }
     impl Clone for HttpClient {
         fn clone(&self) -> Self {
             Self {
                 builder: self.builder.try_clone().unwrap(),
                 database: self.database.clone(),
+                compression: self.compression,
+                format: self.format,
+                session: self.session.clone(),
+            }
+        }
+    }
+
+    /// Session of the HTTP interface, see [HttpClientBuilder::session].
+    #[derive(Clone, Debug)]
+    struct Session {
+        id: String,
+        /// In seconds
+        timeout: Option<u64>,
+        check: bool,
+    }
+
+    /// Builder for [HttpClient], exposing the options of the underlying [reqwest::Client].
+    #[derive(Clone, Debug)]
+    pub struct HttpClientBuilder {
+        url: String,
+        database: Option<String>,
+        username: String,
+        password: Option<String>,
+        compression: Compression,
+        format: ColumnarFormat,
+        session_id: Option<String>,
+        session_timeout: Option<Duration>,
+        session_check: bool,
+        timeout: Option<Duration>,
+        connect_timeout: Option<Duration>,
+        ca_certificates: Vec<Vec<u8>>,
+        identity: Option<Vec<u8>>,
+        proxy: Option<String>,
+        headers: Vec<(String, String)>,
+    }
+    impl HttpClientBuilder {
+        pub fn new(url: &str) -> Self {
+            Self {
+                url: url.into(),
+                database: None,
+                username: "default".into(),
+                password: None,
+                compression: Compression::None,
+                format: ColumnarFormat::Native,
+                session_id: None,
+                session_timeout: None,
+                session_check: false,
+                timeout: None,
+                connect_timeout: None,
+                ca_certificates: vec![],
+                identity: None,
+                proxy: None,
+                headers: vec![],
             }
         }
+        pub fn database(mut self, database: &str) -> Self {
+            self.database = Some(database.into());
+            self
+        }
+        pub fn credentials(mut self, username: &str, password: Option<&str>) -> Self {
+            self.username = username.into();
+            self.password = password.map(String::from);
+            self
+        }
+        /// Ask the server to compress its responses, and compress the inserted data. Only
+        /// [Compression::Zstd] is supported.
+        pub fn compression(mut self, compression: Compression) -> Self {
+            self.compression = compression;
+            self
+        }
+        /// Format of the retrieved and inserted dataframes.
+        pub fn format(mut self, format: ColumnarFormat) -> Self {
+            self.format = format;
+            self
+        }
+        /// Run all queries in a session, so that they share temporary tables and `SET` statements.
+        ///
+        /// The server runs one query at a time per session, and fails with `SESSION_IS_LOCKED`
+        /// otherwise.
+        pub fn session(mut self, id: &str) -> Self {
+            self.session_id = Some(id.into());
+            self
+        }
+        /// Inactivity after which the server closes the session, 60 seconds by default, rounded
+        /// up to whole seconds. This applies to the session set with [HttpClientBuilder::session].
+        pub fn session_timeout(mut self, timeout: Duration) -> Self {
+            self.session_timeout = Some(timeout);
+            self
+        }
+        /// Fail if the session does not exist anymore (e.g. after a timeout), rather than starting
+        /// a new one, whose temporary tables and settings would be missing. This applies to the
+        /// session set with [HttpClientBuilder::session].
+        pub fn session_check(mut self, check: bool) -> Self {
+            self.session_check = check;
+            self
+        }
+        /// Timeout for whole requests, until the response has been fully received.
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+        pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+            self.connect_timeout = Some(timeout);
+            self
+        }
+        /// Trust an additional CA certificate, in PEM format.
+        pub fn ca_certificate_pem(mut self, pem: &[u8]) -> Self {
+            self.ca_certificates.push(pem.to_vec());
+            self
+        }
+        /// Client certificate and private key, in PEM format.
+        pub fn identity_pem(mut self, pem: &[u8]) -> Self {
+            self.identity = Some(pem.to_vec());
+            self
+        }
+        /// Proxy for all requests, e.g. `http://proxy:3128`.
+        pub fn proxy(mut self, url: &str) -> Self {
+            self.proxy = Some(url.into());
+            self
+        }
+        /// Header sent with all requests.
+        pub fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.into(), value.into()));
+            self
+        }
+        pub fn build(self) -> Result<HttpClient, Error> {
+            if self.compression == Compression::Lz4 {
+                return Err(Error::UnsupportedCompression(self.compression));
+            }
+            let mut builder = reqwest::ClientBuilder::new().zstd(true);
+            if let Some(timeout) = self.timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(timeout) = self.connect_timeout {
+                builder = builder.connect_timeout(timeout);
+            }
+            for pem in &self.ca_certificates {
+                builder = builder.add_root_certificate(
+                    reqwest::Certificate::from_pem(pem).map_err(HttpError::from)?,
+                );
+            }
+            if let Some(pem) = &self.identity {
+                builder =
+                    builder.identity(reqwest::Identity::from_pem(pem).map_err(HttpError::from)?);
+            }
+            if let Some(proxy) = &self.proxy {
+                builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(HttpError::from)?);
+            }
+            let mut headers = reqwest::header::HeaderMap::new();
+            for (name, value) in &self.headers {
+                let invalid = || HttpError::InvalidHeader(name.clone());
+                headers.append(
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|_| invalid())?,
+                    reqwest::header::HeaderValue::from_str(value).map_err(|_| invalid())?,
+                );
+            }
+            let client = builder
+                .default_headers(headers)
+                .build()
+                .map_err(HttpError::from)?;
+            Ok(HttpClient {
+                database: self.database.unwrap_or_else(|| "default".into()),
+                compression: self.compression,
+                format: self.format,
+                session: self.session_id.map(|id| Session {
+                    id,
+                    timeout: self
+                        .session_timeout
+                        .map(|timeout| timeout.as_secs_f64().ceil() as u64),
+                    check: self.session_check,
+                }),
+                builder: client
+                    .post(&self.url)
+                    .header(reqwest::header::TRANSFER_ENCODING, "chunked")
+                    .basic_auth(self.username, self.password),
+            })
+        }
     }
 
     impl HttpClient {
+        /// Client with the default options.
+        ///
+        /// Panics if the underlying HTTP client cannot be created.
+        #[deprecated(note = "use HttpClientBuilder, which returns errors and sets further options")]
         pub fn new(
             url: &str,
             default_database: Option<&str>,
             username: &str,
             password: Option<&str>,
         ) -> Self {
+            let mut builder = HttpClientBuilder::new(url).credentials(username, password);
+            if let Some(database) = default_database {
+                builder = builder.database(database);
+            }
+            builder.build().expect("Failed to create HTTP client")
+        }
+        /// Send a query and check the response status.
+        ///
+        /// When a body is given (e.g. inserted blocks), the query is passed as a URL parameter.
+        /// Settings and query parameters are passed as URL parameters as well. The progress
+        /// headers of the response are reported to the handler of the options, even if the query
+        /// failed.
+        async fn send(
+            &self,
+            query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            body: Option<reqwest::Body>,
+            format: ColumnarFormat,
+            options: &QueryOptions,
+        ) -> Result<reqwest::Response, Error> {
+            let query = query.try_into()?.to_string();
+            let mut builder = self.clone().builder.query(&[
+                ("default_format", format.name()),
+                ("database", &self.database),
+            ]);
+            if format == ColumnarFormat::ArrowStream {
+                builder = builder.query(&[("output_format_arrow_string_as_string", "1")]);
+            }
+            if self.compression == Compression::Zstd {
+                // The response is decompressed by reqwest.
+                builder = builder.query(&[("enable_http_compression", "1")]);
+            }
+            for (name, value) in &options.settings {
+                builder = builder.query(&[(name, value.to_string())]);
+            }
+            for (name, value) in &options.params {
+                builder = builder.query(&[(format!("param_{}", name), value.text())]);
+            }
+            if options.progress.is_some() {
+                builder = builder.query(&[("send_progress_in_http_headers", "1")]);
+            }
+            if let Some(query_id) = &options.query_id {
+                builder = builder.query(&[("query_id", query_id)]);
+            }
+            if let Some(session) = &self.session {
+                builder = builder.query(&[("session_id", &session.id)]);
+                if let Some(timeout) = session.timeout {
+                    builder = builder.query(&[("session_timeout", timeout)]);
+                }
+                if session.check {
+                    builder = builder.query(&[("session_check", "1")]);
+                }
+            }
+            let start = std::time::Instant::now();
+            builder = match body {
+                Some(body) => {
+                    if self.compression == Compression::Zstd {
+                        builder = builder.header(reqwest::header::CONTENT_ENCODING, "zstd");
+                    }
+                    builder.query(&[("query", query)]).body(body)
+                }
+                None => builder.body(query),
+            };
+            let resp = builder.send().await.map_err(HttpError::from)?;
+            // Also reported on failures, e.g. with the rows written before an insert failed.
+            if let Some(handler) = &options.progress {
+                let headers = resp.headers();
+                for header in headers
+                    .get_all("X-ClickHouse-Progress")
+                    .iter()
+                    .chain(headers.get_all("X-ClickHouse-Summary"))
+                {
+                    if let Ok(header) = header.to_str() {
+                        handler.report(Progress::from_header(header, start.elapsed()));
+                    }
+                }
+            }
+            let status = resp.status();
+            // Set when the query failed before the headers were sent, even with a success status.
+            let exception_code = resp
+                .headers()
+                .get("X-ClickHouse-Exception-Code")
+                .and_then(|code| code.to_str().ok()?.parse::<i32>().ok());
+            if !status.is_success() || exception_code.is_some() {
+                let text = resp.text().await.unwrap_or_default();
+                return Err(match (ServerException::parse(&text), exception_code) {
+                    (Some(e), _) => Error::Server(e),
+                    (None, Some(code)) => {
+                        Error::Server(ServerException::new(code, "DB::Exception", &text, ""))
+                    }
+                    (None, None) => HttpError::Server {
+                        status: status.as_u16(),
+                        message: text,
+                    }
+                    .into(),
+                });
+            }
+            Ok(resp)
+        }
+    }
+
+    /// Last bytes of a response body, to recover the exception that Clickhouse writes at the end
+    /// of the body when the query fails after the response has started.
+    #[derive(Clone, Default)]
+    struct Tail(Arc<Mutex<Vec<u8>>>);
+    impl Tail {
+        /// Bytes kept, enough for an exception with its stack trace.
+        const SIZE: usize = 64 * 1024;
+        /// Reader of a response body, recording its last bytes.
+        fn reader(
+            &self,
+            resp: reqwest::Response,
+        ) -> impl tokio::io::AsyncBufRead + Unpin + Send + Sync + 'static {
+            let tail = self.clone();
+            tokio_util::io::StreamReader::new(
+                resp.bytes_stream()
+                    .inspect_ok(move |bytes| {
+                        let mut tail = tail.0.lock().unwrap();
+                        tail.extend_from_slice(bytes);
+                        if tail.len() > 2 * Self::SIZE {
+                            let excess = tail.len() - Self::SIZE;
+                            tail.drain(..excess);
+                        }
+                    })
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+            )
+        }
+        /// The server exception at the end of the body if there is one, and otherwise the error
+        /// with which the body could not be decoded. The rest of the body must have been read.
+        fn exception_or(&self, error: Error) -> Error {
+            match ServerException::find(&self.0.lock().unwrap()) {
+                Some(e) => Error::Server(e),
+                None => error,
+            }
+        }
+    }
+
+    impl HttpClient {
+        /// Body of an `INSERT` query, with each block compressed as a Zstandard frame with
+        /// [Compression::Zstd] (the frames of a stream are decompressed one after the other).
+        fn insert_body(
+            &self,
+            blocks: impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync + 'static,
+        ) -> reqwest::Body {
+            if self.compression == Compression::Zstd {
+                reqwest::Body::wrap_stream(blocks.map(|block| {
+                    Ok::<_, Error>(zstd::bulk::compress(
+                        &block?,
+                        zstd::DEFAULT_COMPRESSION_LEVEL,
+                    )?)
+                }))
+            } else {
+                reqwest::Body::wrap_stream(blocks)
+            }
+        }
+    }
+
+    /// Check that the body of an `INSERT` response, which is otherwise empty, has no exception.
+    async fn check_insert_response(resp: reqwest::Response) -> Result<(), Error> {
+        let body = resp.bytes().await.map_err(HttpError::from)?;
+        match ServerException::find(&body) {
+            Some(e) => Err(Error::Server(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Kills the query on the server when dropped, unless it is marked as finished. This stops
+    /// queries whose results are abandoned, and inserts whose future is dropped, which the server
+    /// otherwise keeps running.
+    struct KillOnDrop {
+        client: HttpClient,
+        query_id: String,
+        finished: bool,
+    }
+    impl KillOnDrop {
+        /// Assign an id to the query if it does not have one.
+        fn new(client: &HttpClient, options: &mut QueryOptions) -> Self {
+            let query_id = options
+                .query_id
+                .get_or_insert_with(|| klickhouse::Uuid::new_v4().to_string())
+                .clone();
             Self {
-                database: default_database.unwrap_or("default").into(),
-                builder: reqwest::ClientBuilder::new()
-                    .zstd(true)
-                    .build()
-                    .unwrap()
-                    .post(url)
-                    .header(reqwest::header::TRANSFER_ENCODING, "chunked")
-                    .basic_auth(username, password),
+                client: client.clone(),
+                query_id,
+                finished: false,
             }
         }
     }
+    impl Drop for KillOnDrop {
+        fn drop(&mut self) {
+            if self.finished {
+                return;
+            }
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                return;
+            };
+            // Outside of the session, which is locked by the query.
+            let mut client = self.client.clone();
+            client.session = None;
+            let query = format!(
+                "KILL QUERY WHERE query_id = {} ASYNC",
+                crate::SettingValue::String(self.query_id.clone()).sql()
+            );
+            debug!(query_id = self.query_id, "Killing abandoned query");
+            runtime.spawn(async move {
+                if let Err(e) = client
+                    .send(query, None, ColumnarFormat::Native, &Default::default())
+                    .await
+                {
+                    warn!("Failed to kill abandoned query: {}", e);
+                }
+            });
+        }
+    }
 
     impl ClientGeneric for HttpClient {
         fn sends_initial_block(&self) -> bool {
@@ -187,36 +983,41 @@ pub mod http {
         async fn query_raw(
             &self,
             query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
-        ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin, Error> {
+            mut options: QueryOptions,
+        ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin + 'static, Error> {
+            let mut kill = KillOnDrop::new(self, &mut options);
             let resp = self
-                .clone()
-                .builder
-                .query(&[("default_format", "Native"), ("database", &self.database)])
-                .body(query.try_into()?.to_string())
-                .send()
+                .send(query, None, ColumnarFormat::Native, &options)
                 .await
-                .map_err(HttpError::from)?;
-            if !resp.status().is_success() {
-                return Err(HttpError::Server(resp.text().await.unwrap_or_default()).into());
-            }
-            let reader = tokio_util::io::StreamReader::new(
-                resp.bytes_stream()
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
-            );
-            let stream = stream::unfold(reader, |mut reader| async {
-                match reader.fill_buf().await {
-                    Err(e) => {
-                        return Some((Err(HttpError::IO(e).into()), reader));
-                    }
-                    Ok(buf) if buf.is_empty() => {
-                        return None;
+                .map_err(|e| {
+                    kill.finished = true;
+                    e
+                })?;
+            let tail = Tail::default();
+            let reader = tail.reader(resp);
+            let stream = stream::unfold((reader, kill), move |(mut reader, mut kill)| {
+                let tail = tail.clone();
+                async move {
+                    match reader.fill_buf().await {
+                        Err(e) => {
+                            return Some((Err(HttpError::IO(e).into()), (reader, kill)));
+                        }
+                        Ok(buf) if buf.is_empty() => {
+                            kill.finished = true;
+                            return None;
+                        }
+                        _ => {}
                     }
-                    _ => {}
+                    let block = match Block::read(&mut reader, 0).await {
+                        Ok(block) => Ok(block),
+                        Err(e) => {
+                            let mut rest = (&mut reader).take(Tail::SIZE as u64);
+                            let _ = tokio::io::copy(&mut rest, &mut tokio::io::sink()).await;
+                            Err(tail.exception_or(e.into()))
+                        }
+                    };
+                    Some((block, (reader, kill)))
                 }
-                Some((
-                    Block::read(&mut reader, 0).await.map_err(Error::from),
-                    reader,
-                ))
             });
             // Repeat the first block in lieu of an initial block
             let mut stream = Box::pin(stream.fuse());
@@ -233,10 +1034,520 @@ pub mod http {
         }
         async fn insert_native_raw(
             &self,
-            _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
-            _blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
-        ) -> Result<impl Stream<Item = Result<Block, Error>>, Error> {
-            Err::<stream::Empty<_>, _>(HttpError::Insertion.into())
+            query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
+            mut options: QueryOptions,
+        ) -> Result<impl Stream<Item = Result<Block, Error>> + 'static, Error> {
+            // The blocks are converted to series to be encoded by the columnar codec.
+            let body = self.insert_body(blocks.map(native::write_value_block));
+            let mut kill = KillOnDrop::new(self, &mut options);
+            let result = async {
+                let resp = self
+                    .send(query, Some(body), ColumnarFormat::Native, &options)
+                    .await?;
+                check_insert_response(resp).await
+            }
+            .await;
+            kill.finished = true;
+            result?;
+            Ok(stream::empty::<Result<Block, Error>>())
+        }
+        fn supports_columnar(&self) -> bool {
+            true
+        }
+        fn columnar_format(&self) -> ColumnarFormat {
+            self.format
+        }
+        async fn query_columnar(
+            &self,
+            query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            mut options: QueryOptions,
+        ) -> Result<BoxStream<'static, Result<SeriesBlock, Error>>, Error> {
+            let mut kill = KillOnDrop::new(self, &mut options);
+            let resp = self
+                .send(query, None, self.format, &options)
+                .await
+                .map_err(|e| {
+                    kill.finished = true;
+                    e
+                })?;
+            let tail = Tail::default();
+            let reader = tokio_util::io::SyncIoBridge::new(tail.reader(resp));
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            // Decode on a blocking thread, while the next bytes are being received.
+            let format = self.format;
+            tokio::task::spawn_blocking(move || {
+                let mut reader = std::io::BufReader::new(reader);
+                let blocks: Box<dyn Iterator<Item = Result<SeriesBlock, Error>> + '_> = match format
+                {
+                    ColumnarFormat::Native => Box::new(std::iter::from_fn(|| {
+                        native::read_block(&mut reader).transpose()
+                    })),
+                    ColumnarFormat::ArrowStream => crate::arrow::read_blocks(&mut reader),
+                };
+                let mut failure = None;
+                for block in blocks {
+                    match block {
+                        Ok(block) => {
+                            if tx.blocking_send(Ok(block)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            failure = Some(e);
+                            break;
+                        }
+                    }
+                }
+                if let Some(e) = failure {
+                    let mut rest = std::io::Read::take(&mut reader, Tail::SIZE as u64);
+                    let _ = std::io::copy(&mut rest, &mut std::io::sink());
+                    let _ = tx.blocking_send(Err(tail.exception_or(e)));
+                }
+            });
+            Ok(stream::unfold((rx, kill), |(mut rx, mut kill)| async move {
+                match rx.recv().await {
+                    Some(block) => Some((block, (rx, kill))),
+                    None => {
+                        kill.finished = true;
+                        None
+                    }
+                }
+            })
+            .boxed())
+        }
+        async fn insert_columnar(
+            &self,
+            query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            blocks: impl Stream<Item = Vec<u8>> + Send + Sync + Unpin + 'static,
+            mut options: QueryOptions,
+        ) -> Result<(), Error> {
+            let body = self.insert_body(blocks.map(Ok));
+            let mut kill = KillOnDrop::new(self, &mut options);
+            let result = async {
+                let resp = self.send(query, Some(body), self.format, &options).await?;
+                check_insert_response(resp).await
+            }
+            .await;
+            kill.finished = true;
+            result
         }
     }
+
+    #[cfg(test)]
+    mod test {
+        use polars::prelude::NamedFrom;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::*;
+
+        const EMPTY_RESPONSE: &str = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+
+        /// Accept one connection and return the URL of the server, and the received request once
+        /// it has been answered with the response. Without a response, the connection is kept open
+        /// without responding.
+        async fn serve_once(
+            response: Option<&'static str>,
+        ) -> (String, tokio::task::JoinHandle<String>) {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let handle = tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                // Until the body has been received, after the end of the headers.
+                let idle = Duration::from_millis(200);
+                loop {
+                    let headers_end = request.windows(4).any(|w| w == b"\r\n\r\n");
+                    match tokio::time::timeout(idle, socket.read(&mut buf)).await {
+                        Ok(Ok(0)) => break,
+                        Ok(Ok(n)) => request.extend_from_slice(&buf[..n]),
+                        Err(_) if headers_end => break,
+                        Err(_) => {}
+                        Ok(Err(e)) => panic!("{}", e),
+                    }
+                }
+                if let Some(response) = response {
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                } else {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                String::from_utf8_lossy(&request).to_lowercase()
+            });
+            (url, handle)
+        }
+        #[tokio::test]
+        async fn builder_headers() -> anyhow::Result<()> {
+            let (url, server) = serve_once(Some(EMPTY_RESPONSE)).await;
+            HttpClientBuilder::new(&url)
+                .credentials("user", Some("pass"))
+                .header("X-Request-Source", "test")
+                .build()?
+                .execute("SELECT 1")
+                .await?;
+            let request = server.await?;
+            assert!(request.contains("x-request-source: test\r\n"));
+            // Base64 of `user:pass`
+            assert!(request.contains("authorization: basic dxnlcjpwyxnz\r\n"));
+
+            assert!(matches!(
+                HttpClientBuilder::new(&url)
+                    .header("X-Invalid Name", "test")
+                    .build(),
+                Err(Error::Http(HttpError::InvalidHeader(name))) if name == "X-Invalid Name"
+            ));
+            Ok(())
+        }
+        #[tokio::test]
+        async fn insert_written_rows() -> anyhow::Result<()> {
+            // The insert fails after two blocks of two rows were written.
+            let (url, _server) = serve_once(Some(
+                "HTTP/1.1 500 Internal Server Error\r\nX-ClickHouse-Summary: {\"written_rows\":\"4\"}\r\ncontent-length: 72\r\n\r\nCode: 241. DB::Exception: Memory limit exceeded. (MEMORY_LIMIT_EXCEEDED)",
+            ))
+            .await;
+            let client = HttpClientBuilder::new(&url).build()?;
+            let table = crate::ClickhouseTable {
+                name: "test".into(),
+                types: [("a".to_string(), "Int64".parse()?)].into_iter().collect(),
+            };
+            let df = polars::df!("a" => [1i64, 2, 3, 4, 5, 6])?;
+            let options = crate::InsertOptions {
+                max_block_rows: Some(2),
+                ..Default::default()
+            };
+            match table.insert_df(df, options, &client).await {
+                Err(Error::Insertion {
+                    rows,
+                    written_rows,
+                    source,
+                }) => {
+                    assert_eq!((rows, written_rows), (6, Some(4)));
+                    assert!(
+                        matches!(*source, Error::Server(ref e) if e.name == "MEMORY_LIMIT_EXCEEDED")
+                    );
+                }
+                r => panic!("Expected an insertion error, got {:?}", r),
+            }
+            Ok(())
+        }
+        #[tokio::test]
+        async fn builder_timeout() -> anyhow::Result<()> {
+            let (url, _server) = serve_once(None).await;
+            let client = HttpClientBuilder::new(&url)
+                .timeout(Duration::from_millis(200))
+                .build()?;
+            match client.execute("SELECT 1").await {
+                Err(Error::Http(HttpError::Request(e))) => assert!(e.is_timeout()),
+                r => panic!("Expected a timeout, got {:?}", r),
+            }
+            Ok(())
+        }
+        #[tokio::test]
+        async fn insert_compression() -> anyhow::Result<()> {
+            let (url, server) = serve_once(Some(EMPTY_RESPONSE)).await;
+            HttpClientBuilder::new(&url)
+                .compression(Compression::Zstd)
+                .build()?
+                .insert_columnar(
+                    "INSERT INTO t FORMAT Native",
+                    stream::iter(["uncompressed block ".repeat(100).into_bytes()]),
+                    Default::default(),
+                )
+                .await?;
+            let request = server.await?;
+            assert!(request.contains("content-encoding: zstd\r\n"));
+            // Short blocks are stored as is by zstd, hence the repetitions.
+            assert!(!request.contains("uncompressed block uncompressed block"));
+            Ok(())
+        }
+        #[tokio::test]
+        async fn exceptions() -> anyhow::Result<()> {
+            // Before the headers are sent, with a success status.
+            let (url, _server) = serve_once(Some(
+                "HTTP/1.1 200 OK\r\nX-ClickHouse-Exception-Code: 159\r\ncontent-length: 7\r\n\r\nTimeout",
+            ))
+            .await;
+            match HttpClientBuilder::new(&url)
+                .build()?
+                .execute("SELECT 1")
+                .await
+            {
+                Err(Error::Server(e)) => assert_eq!((e.code, e.message.as_str()), (159, "Timeout")),
+                r => panic!("Expected a server exception, got {:?}", r),
+            }
+            // After the results have started.
+            let (url, _server) = serve_once(Some(
+                "HTTP/1.1 200 OK\r\ncontent-length: 72\r\n\r\nCode: 241. DB::Exception: Memory limit exceeded. (MEMORY_LIMIT_EXCEEDED)",
+            ))
+            .await;
+            match HttpClientBuilder::new(&url)
+                .build()?
+                .execute("SELECT 1")
+                .await
+            {
+                Err(Error::Server(e)) => assert_eq!(e.name, "MEMORY_LIMIT_EXCEEDED"),
+                r => panic!("Expected a server exception, got {:?}", r),
+            }
+            Ok(())
+        }
+        #[test]
+        fn builder_certificates() {
+            let builder = HttpClientBuilder::new("https://localhost:8443");
+            assert!(builder
+                .clone()
+                .ca_certificate_pem(include_bytes!("../tests/data/ca.pem"))
+                .build()
+                .is_ok());
+            assert!(builder
+                .ca_certificate_pem(
+                    b"-----BEGIN CERTIFICATE-----\nnot base64\n-----END CERTIFICATE-----\n"
+                )
+                .build()
+                .is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "native-compression"))]
+mod test {
+    use std::time::Duration;
+
+    use klickhouse::ClickhouseWrite;
+    use polars::prelude::NamedFrom;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::ProgressHandler;
+
+    /// Packets of a mock native server.
+    #[derive(Default)]
+    struct Packets(Vec<u8>);
+    impl Packets {
+        async fn hello(mut self) -> Self {
+            self.0.write_var_uint(0).await.unwrap();
+            self.0.write_string("ClickHouse").await.unwrap();
+            for version in [22, 9, 54459] {
+                self.0.write_var_uint(version).await.unwrap();
+            }
+            self.0.write_string("UTC").await.unwrap();
+            self.0.write_string("mock").await.unwrap();
+            self.0.write_var_uint(0).await.unwrap();
+            self
+        }
+        /// Block of a `UInt8` column `x`.
+        async fn data(mut self, values: &[u8]) -> Self {
+            self.0.write_var_uint(1).await.unwrap();
+            self.0.write_string("").await.unwrap();
+            // Block info: not overflows, no bucket
+            self.0.extend([1, 0, 2, 0xff, 0xff, 0xff, 0xff, 0]);
+            self.0.write_var_uint(1).await.unwrap();
+            self.0.write_var_uint(values.len() as u64).await.unwrap();
+            self.0.write_string("x").await.unwrap();
+            self.0.write_string("UInt8").await.unwrap();
+            // No custom serialization
+            self.0.push(0);
+            self.0.extend(values);
+            self
+        }
+        /// Read rows and bytes, total rows to read, written rows and bytes.
+        async fn progress(mut self, values: [u64; 5]) -> Self {
+            self.0.write_var_uint(3).await.unwrap();
+            for value in values {
+                self.0.write_var_uint(value).await.unwrap();
+            }
+            self
+        }
+        /// Rows, blocks and bytes, with an applied limit.
+        async fn profile_info(mut self, values: [u64; 3], rows_before_limit: u64) -> Self {
+            self.0.write_var_uint(6).await.unwrap();
+            for value in values {
+                self.0.write_var_uint(value).await.unwrap();
+            }
+            self.0.push(1);
+            self.0.write_var_uint(rows_before_limit).await.unwrap();
+            self.0.push(1);
+            self
+        }
+        async fn exception(mut self, code: i32, name: &str) -> Self {
+            self.0.write_var_uint(2).await.unwrap();
+            self.0.extend(code.to_le_bytes());
+            for field in [name, "Failure", ""] {
+                self.0.write_string(field).await.unwrap();
+            }
+            // No nested exception
+            self.0.push(0);
+            self
+        }
+        async fn end_of_stream(mut self) -> Self {
+            self.0.write_var_uint(5).await.unwrap();
+            self
+        }
+    }
+
+    /// Serve one native connection, sending each response once the client is idle, and return the
+    /// requests received before each of them.
+    async fn serve_native(
+        responses: Vec<Packets>,
+    ) -> (String, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut requests = vec![];
+            let mut buf = [0u8; 1024];
+            let idle = Duration::from_millis(200);
+            for response in responses {
+                let mut request = vec![];
+                loop {
+                    match tokio::time::timeout(idle, socket.read(&mut buf)).await {
+                        Ok(Ok(0)) => break,
+                        Ok(Ok(n)) => request.extend_from_slice(&buf[..n]),
+                        Err(_) if !request.is_empty() => break,
+                        Err(_) => {}
+                        Ok(Err(e)) => panic!("{}", e),
+                    }
+                }
+                requests.push(request);
+                socket.write_all(&response.0).await.unwrap();
+            }
+            requests
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn native_cancel() -> anyhow::Result<()> {
+        let (address, server) = serve_native(vec![
+            Packets::default().hello().await,
+            // `SET` query run by klickhouse on connection
+            Packets::default().end_of_stream().await,
+            Packets::default().data(&[1, 2]).await,
+            Packets::default().end_of_stream().await,
+        ])
+        .await;
+        let client = Client::connect(&address, None, "default", None, Compression::None).await?;
+        let options = QueryOptions {
+            query_id: Some("abandoned-query".into()),
+            ..Default::default()
+        };
+        let mut blocks = client.query_raw("SELECT x", options).await?;
+        assert_eq!(blocks.next().await.transpose()?.map(|b| b.rows), Some(2));
+        drop(blocks);
+
+        let requests = server.await?;
+        let query = String::from_utf8_lossy(&requests[2]);
+        assert!(query.contains("abandoned-query") && query.contains("SELECT x"));
+        // Cancel packet
+        assert_eq!(requests[3], [3]);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn native_progress() -> anyhow::Result<()> {
+        let response = Packets::default().progress([2, 2, 10, 0, 0]).await;
+        let response = response
+            .data(&[1, 2])
+            .await
+            .profile_info([2, 1, 2], 5)
+            .await;
+        let response = response
+            .progress([3, 3, 0, 0, 0])
+            .await
+            .end_of_stream()
+            .await;
+        let (address, _server) = serve_native(vec![
+            Packets::default().hello().await,
+            Packets::default().end_of_stream().await,
+            response,
+        ])
+        .await;
+        let client = Client::connect(&address, None, "default", None, Compression::None).await?;
+        let (handler, mut reports) = ProgressHandler::channel();
+        let options = QueryOptions {
+            progress: Some(handler),
+            ..Default::default()
+        };
+        let blocks: Vec<_> = client
+            .query_raw("SELECT x LIMIT 2", options)
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(blocks.len(), 1);
+
+        // All reports are received by the end of the results.
+        let mut last = None;
+        while let Ok(progress) = reports.try_recv() {
+            last = Some(progress);
+        }
+        let last = last.expect("no progress reported");
+        assert_eq!((last.read_rows, last.total_rows_to_read), (5, 10));
+        let profile = ProfileInfo {
+            rows: 2,
+            blocks: 1,
+            bytes: 2,
+            rows_before_limit: Some(5),
+        };
+        assert_eq!(last.profile, Some(profile));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn native_insert_written_rows() -> anyhow::Result<()> {
+        // The insert fails after two blocks of two rows were written.
+        let response = Packets::default().progress([0, 0, 0, 4, 32]).await;
+        let (address, _server) = serve_native(vec![
+            Packets::default().hello().await,
+            Packets::default().end_of_stream().await,
+            response.exception(241, "DB::Exception").await,
+        ])
+        .await;
+        let client = Client::connect(&address, None, "default", None, Compression::None).await?;
+        let table = crate::ClickhouseTable {
+            name: "test".into(),
+            types: [("a".to_string(), "Int64".parse()?)].into_iter().collect(),
+        };
+        let df = polars::df!("a" => [1i64, 2, 3, 4, 5, 6])?;
+        let options = crate::InsertOptions {
+            max_block_rows: Some(2),
+            ..Default::default()
+        };
+        match table.insert_df(df, options, &client).await {
+            Err(Error::Insertion {
+                rows,
+                written_rows,
+                source,
+            }) => {
+                assert_eq!((rows, written_rows), (6, Some(4)));
+                assert!(matches!(*source, Error::Server(ref e) if e.code == 241));
+            }
+            r => panic!("Expected an insertion error, got {:?}", r),
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn native_params() -> anyhow::Result<()> {
+        let (address, server) = serve_native(vec![
+            Packets::default().hello().await,
+            Packets::default().end_of_stream().await,
+            Packets::default().end_of_stream().await,
+        ])
+        .await;
+        let client = Client::connect(&address, None, "default", None, Compression::None).await?;
+        let options = QueryOptions {
+            params: [("name".into(), "O'Brien\t".into())].into_iter().collect(),
+            ..Default::default()
+        };
+        let blocks: Vec<_> = client
+            .query_raw("SELECT {name:String}", options)
+            .await?
+            .try_collect()
+            .await?;
+        assert!(blocks.is_empty());
+
+        let requests = server.await?;
+        // Name, custom setting flag, and quoted escaped text of the value, then the end of the
+        // parameters
+        let params = b"SELECT {name:String}\x04name\x02\x0d'O\\'Brien\\\\t'\x00";
+        assert!(requests[2].windows(params.len()).any(|w| w == params));
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
 use std::collections::HashSet;
+use std::sync::OnceLock;
 
+use itertools::Itertools;
 use polars::prelude::*;
 
 use super::ClickhouseType;
@@ -9,7 +11,9 @@ pub enum Error {
     #[error("Polars error: {0}")]
     Polars(#[from] PolarsError),
     #[error("Klickhouse error: {0}")]
-    Klickhouse(#[from] klickhouse::KlickhouseError),
+    Klickhouse(klickhouse::KlickhouseError),
+    #[error("Server exception: {0}")]
+    Server(ServerException),
     #[error("A Clickhouse client is required in ClickhouseTable")]
     MissingClient,
     #[error("Unsupported Polars data type {0}")]
@@ -28,12 +32,146 @@ pub enum Error {
     UnexpectedNull(&'static str),
     #[error("Column {0} returned by Clickhouse is not present locally")]
     MissingColumnLocal(String),
-    #[error("Dataframe should be rechunked")]
-    ShouldRechunk,
     #[error("The constructed series do not have the same lengths: {0:?}")]
     MismatchingLengths(HashSet<usize>),
     #[error("HTTP error: {0}")]
     Http(#[from] HttpError),
+    #[error("Non-finite value in column {0} at row {1}")]
+    NonFiniteValue(String, usize),
+    #[error("Value {0} out of range for {1}")]
+    DecimalOverflow(f64, ClickhouseType),
+    #[error("Timestamp {0}s out of range for {1}")]
+    DateTimeOverflow(i64, ClickhouseType),
+    #[error("Date {0} days after the epoch out of range for {1}")]
+    DateOverflow(i32, ClickhouseType),
+    #[error("Invalid UUID {0}")]
+    InvalidUuid(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The client does not support columnar blocks")]
+    ColumnarUnsupported,
+    #[error("Incompatible columns: {}", .0.iter().join("; "))]
+    IncompatibleColumns(Vec<IncompatibleColumn>),
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Compression {0:?} is not supported by this client")]
+    UnsupportedCompression(crate::Compression),
+    #[error("Invalid setting name {0}")]
+    InvalidSetting(String),
+    #[error("Query cancelled")]
+    Cancelled,
+    #[error("Query timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("Insertion failed after sending {rows} rows: {source}")]
+    Insertion {
+        /// Rows sent to the server.
+        rows: usize,
+        /// Rows written as last reported by the server, if it reported progress.
+        written_rows: Option<u64>,
+        source: Box<Error>,
+    },
+}
+
+impl From<klickhouse::KlickhouseError> for Error {
+    fn from(e: klickhouse::KlickhouseError) -> Self {
+        match e {
+            klickhouse::KlickhouseError::ServerException {
+                code,
+                name,
+                message,
+                stack_trace,
+            } => Self::Server(ServerException::new(code, &name, &message, &stack_trace)),
+            e => Self::Klickhouse(e),
+        }
+    }
+}
+
+/// Exception raised by the Clickhouse server, with either client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerException {
+    pub code: i32,
+    /// Name of the error code, e.g. `UNKNOWN_TABLE` or `TOO_MANY_PARTS`, if reported by the
+    /// server, and otherwise the exception class (e.g. `DB::Exception`).
+    pub name: String,
+    pub message: String,
+    pub stack_trace: String,
+}
+impl ServerException {
+    /// Normalize an exception, extracting the error code name from the message.
+    pub(crate) fn new(code: i32, name: &str, message: &str, stack_trace: &str) -> Self {
+        static MESSAGE: OnceLock<regex::Regex> = OnceLock::new();
+        let regex = MESSAGE.get_or_init(|| {
+            regex::Regex::new(
+                r"(?s)^(?:[\w:]+Exception: )?(.*?)(?: \(([A-Z][A-Z0-9_]*)\))?(?: \(version .*\))?\s*$",
+            )
+            .unwrap()
+        });
+        // The regex matches any string.
+        let captures = regex.captures(message).unwrap();
+        Self {
+            code,
+            name: captures.get(2).map_or(name, |m| m.as_str()).to_string(),
+            message: captures[1].to_string(),
+            stack_trace: stack_trace.trim().to_string(),
+        }
+    }
+    /// Parse an exception from the body of an HTTP response, e.g.
+    /// `Code: 60. DB::Exception: Table default.x does not exist. (UNKNOWN_TABLE) (version 24.3.1)`.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        static HEADER: OnceLock<regex::Regex> = OnceLock::new();
+        let regex = HEADER.get_or_init(|| {
+            regex::Regex::new(r"(?s)^\s*Code: (-?\d+)\. (?:([\w:]+): )?(.*)$").unwrap()
+        });
+        let (text, stack_trace) = text.split_once("Stack trace:").unwrap_or((text, ""));
+        let captures = regex.captures(text)?;
+        Some(Self::new(
+            captures[1].parse().ok()?,
+            captures.get(2).map_or("", |m| m.as_str()),
+            &captures[3],
+            stack_trace,
+        ))
+    }
+    /// Find an exception at the end of a response body, where Clickhouse writes it when the query
+    /// fails after the response has started.
+    pub(crate) fn find(body: &[u8]) -> Option<Self> {
+        static START: OnceLock<regex::bytes::Regex> = OnceLock::new();
+        let start = START
+            .get_or_init(|| regex::bytes::Regex::new(r"Code: -?\d+\. [\w:]*Exception").unwrap())
+            .find(body)?
+            .start();
+        let text = String::from_utf8_lossy(&body[start..]);
+        // Recent servers delimit the exception with `__exception__` markers.
+        Self::parse(text.split("__exception__").next().unwrap_or_default())
+    }
+}
+impl std::fmt::Display for ServerException {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.name, self.code, self.message)
+    }
+}
+
+/// Column that cannot be inserted into a table, see
+/// [ClickhouseTable::validate_schema](crate::ClickhouseTable::validate_schema).
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncompatibleColumn {
+    pub column: String,
+    pub dtype: DataType,
+    pub type_: ClickhouseType,
+    /// Polars data type to cast the column to before insertion, if any.
+    pub suggested_cast: Option<DataType>,
+}
+impl std::fmt::Display for IncompatibleColumn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` of type {} cannot be inserted as {}",
+            self.column, self.dtype, self.type_
+        )?;
+        if let Some(cast) = &self.suggested_cast {
+            write!(f, " (cast to {})", cast)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -42,8 +180,44 @@ pub enum HttpError {
     Request(#[from] reqwest::Error),
     #[error("I/O error: {0}")]
     IO(std::io::Error),
-    #[error("Polars to Clickhouse unsupported with HTTP client")]
-    Insertion,
-    #[error("Server error: {0}")]
-    Server(String),
+    /// Error status without a server exception in the body, e.g. from a proxy.
+    #[error("Server error {status}: {message}")]
+    Server { status: u16, message: String },
+    #[error("Invalid header {0}")]
+    InvalidHeader(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn server_exception() {
+        let e = ServerException::parse(
+            "Code: 60. DB::Exception: Table default.x does not exist. (UNKNOWN_TABLE) (version 24.3.1.2672 (official build))\n",
+        )
+        .unwrap();
+        assert_eq!(e.code, 60);
+        assert_eq!(e.name, "UNKNOWN_TABLE");
+        assert_eq!(e.message, "Table default.x does not exist.");
+        assert!(e.stack_trace.is_empty());
+
+        let e = ServerException::parse(
+            "Code: 62. DB::Exception: Syntax error\n\nStack trace:\n\n0. x\n",
+        )
+        .unwrap();
+        assert_eq!(e.name, "DB::Exception");
+        assert_eq!(e.message, "Syntax error");
+        assert_eq!(e.stack_trace, "0. x");
+
+        assert!(ServerException::parse("Bad gateway").is_none());
+
+        let mut body = vec![0u8, 3, 255, b'C'];
+        body.extend_from_slice(
+            b"Code: 241. DB::Exception: Memory limit exceeded. (MEMORY_LIMIT_EXCEEDED)\n",
+        );
+        let e = ServerException::find(&body).unwrap();
+        assert_eq!(e.code, 241);
+        assert_eq!(e.name, "MEMORY_LIMIT_EXCEEDED");
+        assert!(ServerException::find(b"\x00\x01Code: none").is_none());
+    }
 }
@@ -1,15 +1,30 @@
 #![doc = include_str!("../README.md")]
 
+mod arrow;
 mod c2p;
 mod clickhouse;
-pub use clickhouse::{http::HttpClient, Client, ClientGeneric};
+pub use clickhouse::{
+    http::{HttpClient, HttpClientBuilder},
+    Client, ClientGeneric, ColumnarFormat, Compression,
+};
 mod table;
-pub use table::{ClickhouseTable, TableCreationOptions};
+pub use table::{ClickhouseTable, InsertOptions, TableCreationOptions};
 mod errors;
 mod structs;
 pub use errors::*;
+mod native;
+pub use native::SeriesBlock;
 mod p2c;
-pub use c2p::{get_df_query, GetOptions};
+mod query;
+mod retry;
+pub use c2p::{get_df_query, get_df_stream, ColumnPattern, DescribeCache, GetOptions};
+pub use p2c::{Coercion, DecimalRounding, NonFinitePolicy};
+pub use query::{
+    ParamValue, Params, ProfileInfo, Progress, ProgressHandler, QueryOptions, SettingValue,
+    Settings,
+};
+pub use retry::RetryPolicy;
+pub use tokio_util::sync::CancellationToken;
 
 use std::str::FromStr;
 
@@ -21,12 +36,20 @@ pub enum ClickhouseType {
     Native(klickhouse::Type),
     Bool,
     Json,
+    /// UUID represented as its 16 bytes ([polars::datatypes::DataType::Binary]) rather than a string.
+    BinaryUuid,
     Nullable(Box<ClickhouseType>),
 }
 impl ClickhouseType {
     pub fn nullable(self) -> ClickhouseType {
         Self::Nullable(Box::new(self))
     }
+    pub fn is_nullable(&self) -> bool {
+        matches!(
+            self,
+            Self::Nullable(_) | Self::Native(klickhouse::Type::Nullable(_))
+        )
+    }
 }
 impl FromStr for ClickhouseType {
     type Err = Error;
@@ -34,7 +57,7 @@ impl FromStr for ClickhouseType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "Bool" {
             return Ok(Self::Bool);
-        } else if s == "Nullable(Boolean)" {
+        } else if s == "Nullable(Boolean)" || s == "Nullable(Bool)" {
             return Ok(ClickhouseType::Nullable(Box::new(ClickhouseType::Bool)));
         }
         Ok(Self::Native(klickhouse::Type::from_str(s)?))
@@ -53,6 +76,7 @@ impl From<ClickhouseType> for klickhouse::Type {
             ClickhouseType::Native(n) => n,
             ClickhouseType::Bool => klickhouse::Type::UInt8,
             ClickhouseType::Json => klickhouse::Type::String,
+            ClickhouseType::BinaryUuid => klickhouse::Type::Uuid,
             ClickhouseType::Nullable(n) => {
                 klickhouse::Type::Nullable(Box::new(n.as_ref().clone().into()))
             }
@@ -66,6 +90,7 @@ impl std::fmt::Display for ClickhouseType {
             ClickhouseType::Native(n) => write!(f, "{}", n),
             ClickhouseType::Bool => write!(f, "Bool"),
             ClickhouseType::Json => write!(f, "String"),
+            ClickhouseType::BinaryUuid => write!(f, "UUID"),
             ClickhouseType::Nullable(n) => write!(f, "Nullable({})", n),
         }
     }
@@ -0,0 +1,856 @@
+//! Columnar codec for the Clickhouse Native format, converting directly between column bytes and
+//! [Series] without going through [klickhouse::Value].
+
+use std::io::{BufRead, Read};
+
+use itertools::Itertools;
+use klickhouse::IndexMap;
+use polars::export::arrow::{array::Array, bitmap::Bitmap};
+use polars::prelude::*;
+use rayon::prelude::*;
+
+use super::{ClickhouseType, Error};
+use crate::{
+    c2p,
+    p2c::{self, DecimalRounding},
+};
+
+/// Version of the `LowCardinality` serialization (shared dictionaries with additional keys).
+const LOW_CARDINALITY_VERSION: u64 = 1;
+const LOW_CARDINALITY_HAS_ADDITIONAL_KEYS: u64 = 1 << 9;
+const LOW_CARDINALITY_INDEX_U32: u64 = 2;
+
+/// Block of the Native format, decoded into [Series].
+#[derive(Clone, Debug)]
+pub struct SeriesBlock {
+    pub rows: usize,
+    pub column_types: IndexMap<String, ClickhouseType>,
+    pub column_data: IndexMap<String, Series>,
+}
+
+/// Fixed-size values, stored in little endian.
+trait Primitive: Sized + Copy + Default {
+    const SIZE: usize;
+    fn from_le(bytes: &[u8]) -> Self;
+    fn write_le(self, out: &mut Vec<u8>);
+}
+macro_rules! primitive {
+    ($($t: ty),*) => {
+        $(
+            impl Primitive for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                fn from_le(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().unwrap())
+                }
+                fn write_le(self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes())
+                }
+            }
+        )*
+    };
+}
+primitive!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, f32, f64);
+
+fn read_varint(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut out = 0u64;
+    let mut buf = [0u8];
+    for i in 0..10 {
+        reader.read_exact(&mut buf)?;
+        let byte = buf[0];
+        out |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(out);
+        }
+    }
+    Err(klickhouse::KlickhouseError::ProtocolError("Invalid varint".into()).into())
+}
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let len = read_varint(reader)?;
+    let len = usize::try_from(len).map_err(|_| invalid_length(len))?;
+    read_vec::<u8>(reader, len)
+}
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn invalid_length(len: impl std::fmt::Display) -> Error {
+    klickhouse::KlickhouseError::ProtocolError(format!("Invalid length {}", len)).into()
+}
+
+/// Read `len` values. The lengths come from the stream, so the buffer grows as the bytes are
+/// received rather than being allocated upfront.
+fn read_vec<T: Primitive>(reader: &mut impl Read, len: usize) -> Result<Vec<T>, Error> {
+    let size = len
+        .checked_mul(T::SIZE)
+        .ok_or_else(|| invalid_length(len))?;
+    let mut buf = vec![];
+    reader.by_ref().take(size as u64).read_to_end(&mut buf)?;
+    if buf.len() != size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf.chunks_exact(T::SIZE).map(T::from_le).collect())
+}
+
+/// Read a block, or return `None` at the end of the stream.
+pub(crate) fn read_block(reader: &mut impl BufRead) -> Result<Option<SeriesBlock>, Error> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let columns = read_varint(reader)?;
+    let rows = read_varint(reader)? as usize;
+    let mut block = SeriesBlock {
+        rows,
+        column_types: Default::default(),
+        column_data: Default::default(),
+    };
+    for _ in 0..columns {
+        let name = String::from_utf8_lossy(&read_bytes(reader)?).to_string();
+        let type_: ClickhouseType = String::from_utf8_lossy(&read_bytes(reader)?).parse()?;
+        // Empty blocks have neither prefixes nor data.
+        if rows > 0 {
+            read_prefix(reader, &type_)?;
+        }
+        let mut series = read_column(reader, &type_, rows, None)?;
+        series.rename(&name);
+        block.column_types.insert(name.clone(), type_);
+        block.column_data.insert(name, series);
+    }
+    Ok(Some(block))
+}
+
+fn read_prefix(reader: &mut impl Read, type_: &ClickhouseType) -> Result<(), Error> {
+    match type_ {
+        ClickhouseType::Nullable(inner) => read_prefix(reader, inner),
+        ClickhouseType::Native(
+            klickhouse::Type::Nullable(inner) | klickhouse::Type::Array(inner),
+        ) => read_prefix(reader, &ClickhouseType::from(*inner.clone())),
+        ClickhouseType::Native(klickhouse::Type::LowCardinality(_)) => {
+            let version = read_vec::<u64>(reader, 1)?[0];
+            if version != LOW_CARDINALITY_VERSION {
+                return Err(klickhouse::KlickhouseError::ProtocolError(format!(
+                    "Unsupported LowCardinality version {}",
+                    version
+                ))
+                .into());
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn read_numeric<T>(
+    reader: &mut impl Read,
+    rows: usize,
+    validity: Option<Bitmap>,
+) -> Result<Series, Error>
+where
+    T: PolarsNumericType,
+    T::Native: Primitive,
+    ChunkedArray<T>: IntoSeries,
+{
+    let values = read_vec::<T::Native>(reader, rows)?;
+    Ok(ChunkedArray::<T>::from_vec_validity("", values, validity).into_series())
+}
+
+/// Read a `Decimal` column as floats, the representation used on insertion.
+fn read_decimal<T: Primitive + Into<i128>>(
+    reader: &mut impl Read,
+    scale: usize,
+    rows: usize,
+    validity: Option<Bitmap>,
+) -> Result<Series, Error> {
+    let factor = 10f64.powi(scale as i32);
+    let values = read_vec::<T>(reader, rows)?
+        .into_iter()
+        .map(|x| x.into() as f64 / factor)
+        .collect();
+    Ok(Float64Chunked::from_vec_validity("", values, validity).into_series())
+}
+
+/// Read the data of a column, given the validity of its rows for nullable columns.
+fn read_column(
+    reader: &mut impl Read,
+    type_: &ClickhouseType,
+    rows: usize,
+    validity: Option<Bitmap>,
+) -> Result<Series, Error> {
+    let is_valid = |i: usize| validity.as_ref().map_or(true, |v| v.get_bit(i));
+    Ok(match type_ {
+        ClickhouseType::Native(klickhouse::Type::String) => {
+            let values = (0..rows)
+                .map(|i| {
+                    let bytes = read_bytes(reader)?;
+                    Ok(is_valid(i).then(|| String::from_utf8_lossy(&bytes).to_string()))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            Series::new("", values)
+        }
+
+        ClickhouseType::Bool => {
+            let values = read_vec::<u8>(reader, rows)?;
+            let values: Vec<_> = values
+                .into_iter()
+                .enumerate()
+                .map(|(i, x)| is_valid(i).then_some(x > 0))
+                .collect();
+            Series::new("", values)
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Uuid) => {
+            let values: Vec<_> = read_vec::<u64>(
+                reader,
+                rows.checked_mul(2).ok_or_else(|| invalid_length(rows))?,
+            )?
+            .chunks_exact(2)
+            .enumerate()
+            .map(|(i, x)| {
+                let uuid = (u128::from(x[0]) << 64) | u128::from(x[1]);
+                is_valid(i).then(|| klickhouse::Uuid::from_u128(uuid).to_string())
+            })
+            .collect();
+            Series::new("", values)
+        }
+
+        ClickhouseType::Native(klickhouse::Type::DateTime(tz)) => {
+            // Seconds since the epoch
+            let values = read_vec::<u32>(reader, rows)?
+                .into_iter()
+                .map(|x| i64::from(x) * 1000)
+                .collect();
+            Int64Chunked::from_vec_validity("", values, validity)
+                .into_datetime(TimeUnit::Milliseconds, Some(tz.name().into()))
+                .into_series()
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Date) => {
+            // Days since the epoch
+            let values = read_vec::<u16>(reader, rows)?
+                .into_iter()
+                .map(i32::from)
+                .collect();
+            Int32Chunked::from_vec_validity("", values, validity)
+                .into_date()
+                .into_series()
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Decimal32(scale)) => {
+            read_decimal::<i32>(reader, *scale, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal64(scale)) => {
+            read_decimal::<i64>(reader, *scale, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal128(scale)) => {
+            read_decimal::<i128>(reader, *scale, rows, validity)?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::UInt8) => {
+            read_numeric::<UInt8Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::UInt16) => {
+            read_numeric::<UInt16Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::UInt32) => {
+            read_numeric::<UInt32Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::UInt64) => {
+            read_numeric::<UInt64Type>(reader, rows, validity)?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Int8) => {
+            read_numeric::<Int8Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Int16) => {
+            read_numeric::<Int16Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Int32) => {
+            read_numeric::<Int32Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Int64) => {
+            read_numeric::<Int64Type>(reader, rows, validity)?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Float32) => {
+            read_numeric::<Float32Type>(reader, rows, validity)?
+        }
+        ClickhouseType::Native(klickhouse::Type::Float64) => {
+            read_numeric::<Float64Type>(reader, rows, validity)?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::LowCardinality(inner)) => {
+            read_low_cardinality(reader, &ClickhouseType::from(*inner.clone()), rows)?
+        }
+
+        // Nulls
+        ClickhouseType::Nullable(inner) => read_nullable(reader, inner, rows)?,
+        ClickhouseType::Native(klickhouse::Type::Nullable(inner)) => {
+            read_nullable(reader, &ClickhouseType::from(*inner.clone()), rows)?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Array(inner)) => {
+            let inner = ClickhouseType::from(*inner.clone());
+            let offsets = read_vec::<u64>(reader, rows)?;
+            if offsets.windows(2).any(|w| w[0] > w[1]) {
+                return Err(klickhouse::KlickhouseError::ProtocolError(
+                    "Decreasing array offsets".into(),
+                )
+                .into());
+            }
+            let len = offsets.last().copied().unwrap_or_default();
+            let len = usize::try_from(len).map_err(|_| invalid_length(len))?;
+            let values = read_column(reader, &inner, len, None)?;
+            if rows == 0 {
+                Series::new_empty("", &DataType::List(Box::new(values.dtype().clone())))
+            } else {
+                let mut start = 0;
+                let series: Vec<Series> = offsets
+                    .into_iter()
+                    .map(|end| {
+                        let s = values.slice(start as i64, (end - start) as usize);
+                        start = end;
+                        s
+                    })
+                    .collect();
+                Series::new("", series)
+            }
+        }
+
+        _ => {
+            return Err(Error::UnsupportedClickhouseType(type_.clone()));
+        }
+    })
+}
+
+fn read_nullable(
+    reader: &mut impl Read,
+    inner: &ClickhouseType,
+    rows: usize,
+) -> Result<Series, Error> {
+    let nulls = read_vec::<u8>(reader, rows)?;
+    let validity = Bitmap::from_iter(nulls.into_iter().map(|x| x == 0));
+    read_column(reader, inner, rows, Some(validity))
+}
+
+fn read_low_cardinality(
+    reader: &mut impl Read,
+    inner: &ClickhouseType,
+    rows: usize,
+) -> Result<Series, Error> {
+    let categorical = DataType::Categorical(None, Default::default());
+    if !matches!(
+        klickhouse::Type::from(inner.clone()).strip_null(),
+        klickhouse::Type::String
+    ) {
+        return Err(Error::UnsupportedClickhouseType(ClickhouseType::Native(
+            klickhouse::Type::LowCardinality(Box::new(inner.clone().into())),
+        )));
+    }
+    if rows == 0 {
+        return Ok(Series::new_empty("", &categorical));
+    }
+    let flags = read_vec::<u64>(reader, 1)?[0];
+    if flags & LOW_CARDINALITY_HAS_ADDITIONAL_KEYS == 0 {
+        return Err(klickhouse::KlickhouseError::ProtocolError(
+            "LowCardinality without additional keys".into(),
+        )
+        .into());
+    }
+    let keys = read_vec::<u64>(reader, 1)?[0];
+    let keys = usize::try_from(keys).map_err(|_| invalid_length(keys))?;
+    let keys = read_column(
+        reader,
+        &ClickhouseType::Native(klickhouse::Type::String),
+        keys,
+        None,
+    )?;
+    // One index per row
+    let indices = read_vec::<u64>(reader, 1)?[0];
+    if indices != rows as u64 {
+        return Err(invalid_length(indices));
+    }
+    let indices: Vec<u64> = match flags & 0xff {
+        0 => read_vec::<u8>(reader, rows)?
+            .into_iter()
+            .map(u64::from)
+            .collect(),
+        1 => read_vec::<u16>(reader, rows)?
+            .into_iter()
+            .map(u64::from)
+            .collect(),
+        2 => read_vec::<u32>(reader, rows)?
+            .into_iter()
+            .map(u64::from)
+            .collect(),
+        3 => read_vec::<u64>(reader, rows)?,
+        t => {
+            return Err(klickhouse::KlickhouseError::ProtocolError(format!(
+                "Invalid LowCardinality index type {}",
+                t
+            ))
+            .into())
+        }
+    };
+    // Only the dictionary is converted to a categorical (registered in the global string cache
+    // when enabled); the rows are then mapped to the physical values of its categories.
+    let keys = keys.cast(&categorical)?;
+    let keys = keys.categorical()?;
+    // The first key stands for null in nullable columns.
+    let nullable = inner.is_nullable();
+    let indices: IdxCa = indices
+        .into_iter()
+        .map(|i| (!nullable || i != 0).then_some(i as IdxSize))
+        .collect();
+    let physical = keys.physical().take(&indices)?;
+    // SAFETY: The physical values are taken from a categorical with the same reverse mapping.
+    let values = unsafe {
+        CategoricalChunked::from_cats_and_rev_map_unchecked(
+            physical,
+            keys.get_rev_map().clone(),
+            false,
+            Default::default(),
+        )
+    };
+    Ok(values.into_series())
+}
+
+/// Encode columns into a block of the Native format.
+pub(crate) fn write_block(
+    out: &mut Vec<u8>,
+    columns: &[(&Series, &ClickhouseType)],
+    decimal_rounding: DecimalRounding,
+) -> Result<(), Error> {
+    let rows = columns.first().map(|(s, _)| s.len()).unwrap_or_default();
+    write_varint(out, columns.len() as u64);
+    write_varint(out, rows as u64);
+    // Columns are encoded in parallel.
+    let encoded: Vec<Vec<u8>> = columns
+        .par_iter()
+        .map(|(series, type_)| -> Result<_, Error> {
+            let mut out = vec![];
+            write_bytes(&mut out, series.name().as_bytes());
+            write_bytes(&mut out, type_.to_string().as_bytes());
+            if rows > 0 {
+                write_prefix(&mut out, type_);
+                write_column(&mut out, series, type_, decimal_rounding)?;
+            }
+            Ok(out)
+        })
+        .collect::<Result<_, Error>>()?;
+    out.extend(encoded.into_iter().flatten());
+    Ok(())
+}
+
+/// Encode a block of [klickhouse::Value] into the Native format, through [Series].
+pub(crate) fn write_value_block(block: klickhouse::block::Block) -> Result<Vec<u8>, Error> {
+    let column_types = block.column_types;
+    let columns: Vec<(Series, ClickhouseType)> = block
+        .column_data
+        .into_iter()
+        .map(|(col, values)| -> Result<_, Error> {
+            let type_ = ClickhouseType::from(
+                column_types
+                    .get(&col)
+                    .ok_or_else(|| Error::MissingColumnLocal(col.clone()))?
+                    .clone(),
+            );
+            let mut series = c2p::values_to_series(values, type_.clone(), true)?;
+            series.rename(&col);
+            Ok((series, type_))
+        })
+        .try_collect()?;
+    let mut out = vec![];
+    write_block(
+        &mut out,
+        &columns.iter().map(|c| (&c.0, &c.1)).collect_vec(),
+        Default::default(),
+    )?;
+    Ok(out)
+}
+
+fn write_prefix(out: &mut Vec<u8>, type_: &ClickhouseType) {
+    match type_ {
+        ClickhouseType::Nullable(inner) => write_prefix(out, inner),
+        ClickhouseType::Native(
+            klickhouse::Type::Nullable(inner) | klickhouse::Type::Array(inner),
+        ) => write_prefix(out, &ClickhouseType::from(*inner.clone())),
+        ClickhouseType::Native(klickhouse::Type::LowCardinality(_)) => {
+            LOW_CARDINALITY_VERSION.write_le(out)
+        }
+        _ => {}
+    }
+}
+
+fn write_numeric<T>(out: &mut Vec<u8>, ca: &ChunkedArray<T>)
+where
+    T: PolarsNumericType,
+    T::Native: Primitive,
+{
+    out.reserve(ca.len() * <T::Native as Primitive>::SIZE);
+    for arr in ca.downcast_iter() {
+        for x in arr.values().iter() {
+            x.write_le(out);
+        }
+    }
+}
+
+macro_rules! write_numeric {
+    ($out: ident, $series: ident, $f: ident) => {
+        write_numeric(
+            $out,
+            $series
+                .$f()
+                .map_err(|_| Error::MismatchingSeriesType($series.dtype().clone()))?,
+        )
+    };
+}
+macro_rules! write_decimal {
+    ($out: ident, $series: ident, $type_: ident, $int: ty, $rounding: ident) => {
+        for x in p2c::decimal_values($series, $type_, $rounding)? {
+            // The values are within the precision of the type.
+            (x.unwrap_or_default() as $int).write_le($out);
+        }
+    };
+}
+
+/// Write the data of a column. Null values are written as defaults.
+fn write_column(
+    out: &mut Vec<u8>,
+    series: &Series,
+    type_: &ClickhouseType,
+    decimal_rounding: DecimalRounding,
+) -> Result<(), Error> {
+    let mismatch = || Error::MismatchingSeriesType(series.dtype().clone());
+    match type_ {
+        ClickhouseType::Native(klickhouse::Type::String) => {
+            for x in series.str().map_err(|_| mismatch())? {
+                write_bytes(out, x.unwrap_or_default().as_bytes());
+            }
+        }
+
+        ClickhouseType::Bool => {
+            out.extend(
+                series
+                    .bool()
+                    .map_err(|_| mismatch())?
+                    .into_iter()
+                    .map(|x| u8::from(x.unwrap_or_default())),
+            );
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Uuid) | ClickhouseType::BinaryUuid => {
+            for x in p2c::uuid_values(series)? {
+                let x = x.unwrap_or_default();
+                ((x >> 64) as u64).write_le(out);
+                (x as u64).write_le(out);
+            }
+        }
+
+        ClickhouseType::Native(klickhouse::Type::DateTime(_)) => {
+            for x in p2c::datetime_seconds(series, type_)? {
+                x.unwrap_or_default().write_le(out);
+            }
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Date) => {
+            for x in p2c::date_days(series, type_)? {
+                x.unwrap_or_default().write_le(out);
+            }
+        }
+
+        ClickhouseType::Native(klickhouse::Type::UInt8) => write_numeric!(out, series, u8),
+        ClickhouseType::Native(klickhouse::Type::UInt16) => write_numeric!(out, series, u16),
+        ClickhouseType::Native(klickhouse::Type::UInt32) => write_numeric!(out, series, u32),
+        ClickhouseType::Native(klickhouse::Type::UInt64) => write_numeric!(out, series, u64),
+
+        ClickhouseType::Native(klickhouse::Type::Int8) => write_numeric!(out, series, i8),
+        ClickhouseType::Native(klickhouse::Type::Int16) => write_numeric!(out, series, i16),
+        ClickhouseType::Native(klickhouse::Type::Int32) => write_numeric!(out, series, i32),
+        ClickhouseType::Native(klickhouse::Type::Int64) => write_numeric!(out, series, i64),
+
+        ClickhouseType::Native(klickhouse::Type::Float32) => write_numeric!(out, series, f32),
+        ClickhouseType::Native(klickhouse::Type::Float64) => write_numeric!(out, series, f64),
+
+        ClickhouseType::Native(klickhouse::Type::Decimal32(_)) => {
+            write_decimal!(out, series, type_, i32, decimal_rounding)
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal64(_)) => {
+            write_decimal!(out, series, type_, i64, decimal_rounding)
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal128(_)) => {
+            write_decimal!(out, series, type_, i128, decimal_rounding)
+        }
+
+        ClickhouseType::Native(klickhouse::Type::LowCardinality(s))
+            if s.strip_null() == &klickhouse::Type::String =>
+        {
+            write_low_cardinality(
+                out,
+                series,
+                matches!(s.as_ref(), klickhouse::Type::Nullable(_)),
+            )?
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Array(inner)) => {
+            let inner = ClickhouseType::from(*inner.clone());
+            let ca = series.list().map_err(|_| mismatch())?.rechunk();
+            let values = write_offsets(out, &ca);
+            write_column(out, &values, &inner, decimal_rounding)?;
+        }
+
+        // Nulls
+        ClickhouseType::Nullable(inner) => write_nullable(out, series, inner, decimal_rounding)?,
+        ClickhouseType::Native(klickhouse::Type::Nullable(inner)) => write_nullable(
+            out,
+            series,
+            &ClickhouseType::from(*inner.clone()),
+            decimal_rounding,
+        )?,
+
+        _ => {
+            return Err(Error::UnsupportedClickhouseType(type_.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Write the offsets of a single-chunk list series, and return the values of its rows.
+/// Null rows are written as empty arrays.
+fn write_offsets(out: &mut Vec<u8>, ca: &ListChunked) -> Series {
+    let inner = ca.get_inner();
+    let Some(arr) = ca.downcast_iter().next() else {
+        return inner;
+    };
+    let offsets = arr.offsets().buffer();
+    let start = offsets[0];
+    out.reserve(ca.len() * u64::SIZE);
+    if arr.null_count() == 0 {
+        for end in &offsets[1..] {
+            ((end - start) as u64).write_le(out);
+        }
+        let len = offsets[offsets.len() - 1] - start;
+        return inner.slice(start, len as usize);
+    }
+    // Values of null rows are skipped.
+    let mut keep = Vec::with_capacity(inner.len());
+    keep.resize(start as usize, false);
+    let mut offset = 0u64;
+    for (i, w) in offsets.windows(2).enumerate() {
+        let valid = arr.is_valid(i);
+        if valid {
+            offset += (w[1] - w[0]) as u64;
+        }
+        keep.resize(w[1] as usize, valid);
+        offset.write_le(out);
+    }
+    keep.resize(inner.len(), false);
+    // The mask has the length of the values.
+    inner
+        .filter(&BooleanChunked::from_slice("", &keep))
+        .unwrap()
+}
+
+fn write_nullable(
+    out: &mut Vec<u8>,
+    series: &Series,
+    inner: &ClickhouseType,
+    decimal_rounding: DecimalRounding,
+) -> Result<(), Error> {
+    out.extend(
+        series
+            .is_null()
+            .into_iter()
+            .map(|x| u8::from(x.unwrap_or_default())),
+    );
+    write_column(out, series, inner, decimal_rounding)
+}
+
+/// Write a categorical series as a `LowCardinality` column, whose dictionary contains the
+/// categories present in the series, without materializing the strings of the rows.
+fn write_low_cardinality(out: &mut Vec<u8>, series: &Series, nullable: bool) -> Result<(), Error> {
+    if series.is_empty() {
+        return Ok(());
+    }
+    let ca = series
+        .categorical()
+        .map_err(|_| Error::MismatchingSeriesType(series.dtype().clone()))?;
+    let rev_map = ca.get_rev_map();
+    // Dictionary positions of the physical values. Nulls are written as the first key in nullable
+    // columns, and as empty strings otherwise.
+    let mut keys = IndexMap::<Option<u32>, u32>::default();
+    if nullable {
+        keys.insert(None, 0);
+    }
+    let indices: Vec<u32> = ca
+        .physical()
+        .into_iter()
+        .map(|x| {
+            let len = keys.len() as u32;
+            *keys.entry(x).or_insert(len)
+        })
+        .collect();
+    (LOW_CARDINALITY_INDEX_U32 | LOW_CARDINALITY_HAS_ADDITIONAL_KEYS).write_le(out);
+    (keys.len() as u64).write_le(out);
+    for key in keys.keys() {
+        write_bytes(out, key.map_or("", |x| rev_map.get(x)).as_bytes());
+    }
+    (indices.len() as u64).write_le(out);
+    for i in indices {
+        i.write_le(out);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let columns: Vec<(Series, ClickhouseType)> = [
+            (Series::new("a", &[Some(1i64), None]), "Nullable(Int64)"),
+            (Series::new("b", &["x", "yz"]), "String"),
+            (Series::new("c", &[true, false]), "Bool"),
+            (
+                Series::new(
+                    "d",
+                    &[Series::new("", [1.5f32]), Series::new("", [0f32; 0])],
+                ),
+                "Array(Float32)",
+            ),
+            (
+                Series::new("e", &["x", "x"])
+                    .cast(&DataType::Categorical(None, Default::default()))?,
+                "LowCardinality(String)",
+            ),
+            (
+                Series::new("f", &[None, Some("y")])
+                    .cast(&DataType::Categorical(None, Default::default()))?,
+                "LowCardinality(Nullable(String))",
+            ),
+            (
+                Series::new(
+                    "g",
+                    &[
+                        "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                        "00000000-0000-0000-0000-000000000000",
+                    ],
+                ),
+                "UUID",
+            ),
+            (
+                Int64Chunked::new("h", &[0i64, 1_700_000_000_000])
+                    .into_datetime(TimeUnit::Milliseconds, Some("UTC".into()))
+                    .into_series(),
+                "DateTime('UTC')",
+            ),
+            (
+                Series::new("i", &[Some(-1.25f64), None]),
+                "Nullable(Decimal(9, 2))",
+            ),
+            (Series::new("j", &[1e12f64, 0.001]), "Decimal(38, 3)"),
+            (
+                Series::new("k", &[Some(19_000i32), None]).cast(&DataType::Date)?,
+                "Nullable(Date)",
+            ),
+        ]
+        .into_iter()
+        .map(|(s, type_)| Ok((s, type_.parse::<ClickhouseType>()?)))
+        .collect::<Result<_, Error>>()?;
+        let mut out = vec![];
+        write_block(
+            &mut out,
+            &columns.iter().map(|c| (&c.0, &c.1)).collect::<Vec<_>>(),
+            Default::default(),
+        )?;
+
+        let block = read_block(&mut out.as_slice())?.unwrap();
+        assert_eq!(block.rows, 2);
+        for (series, type_) in &columns {
+            assert_eq!(&block.column_types[series.name()], type_);
+            let decoded = &block.column_data[series.name()];
+            assert_eq!(decoded.dtype(), series.dtype());
+            // Categoricals are compared by value, as their mappings differ.
+            if let DataType::Categorical(..) = series.dtype() {
+                assert!(decoded
+                    .cast(&DataType::String)?
+                    .equals_missing(&series.cast(&DataType::String)?));
+            } else {
+                assert!(decoded.equals_missing(series));
+            }
+        }
+        assert!(read_block(&mut &out[out.len()..])?.is_none());
+        Ok(())
+    }
+    #[test]
+    fn empty_block() -> anyhow::Result<()> {
+        let type_: ClickhouseType = "LowCardinality(Nullable(String))".parse()?;
+        let empty = Series::new_empty("a", &DataType::Categorical(None, Default::default()));
+        let series = Series::new("a", &[Some("x"), None])
+            .cast(&DataType::Categorical(None, Default::default()))?;
+        let mut out = vec![];
+        write_block(&mut out, &[(&empty, &type_)], Default::default())?;
+        // Only the header: column count, row count, name and type.
+        assert_eq!(out.len(), 5 + type_.to_string().len());
+        write_block(&mut out, &[(&series, &type_)], Default::default())?;
+
+        let mut reader = out.as_slice();
+        let block = read_block(&mut reader)?.unwrap();
+        assert_eq!(block.rows, 0);
+        assert!(block.column_data["a"].is_empty());
+        let block = read_block(&mut reader)?.unwrap();
+        assert_eq!(block.rows, 2);
+        assert!(block.column_data["a"]
+            .cast(&DataType::String)?
+            .equals_missing(&series.cast(&DataType::String)?));
+        assert!(read_block(&mut reader)?.is_none());
+        Ok(())
+    }
+    #[test]
+    fn arrays() -> anyhow::Result<()> {
+        let type_: ClickhouseType = "Array(Int32)".parse()?;
+        let series: ListChunked = [
+            Some(Series::new("", [0i32])),
+            Some(Series::new("", [1i32, 2])),
+            None,
+            Some(Series::new("", [3i32])),
+        ]
+        .into_iter()
+        .collect();
+        // Offsets of the slice do not start at 0.
+        let series = series.into_series().slice(1, 3);
+        let mut out = vec![];
+        write_column(&mut out, &series, &type_, Default::default())?;
+        let decoded = read_column(&mut out.as_slice(), &type_, 3, None)?;
+        let decoded: Vec<Vec<i32>> = decoded
+            .list()?
+            .into_iter()
+            .map(|s| Ok(s.unwrap().i32()?.into_no_null_iter().collect()))
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(decoded, vec![vec![1, 2], vec![], vec![3]]);
+        Ok(())
+    }
+    #[test]
+    fn invalid_lengths() {
+        // String of length 2^62, and array with u64::MAX values.
+        let mut string = vec![];
+        write_varint(&mut string, 1 << 62);
+        assert!(read_bytes(&mut string.as_slice()).is_err());
+        let offsets = u64::MAX.to_le_bytes();
+        assert!(read_column(
+            &mut offsets.as_slice(),
+            &"Array(UInt64)".parse().unwrap(),
+            1,
+            None
+        )
+        .is_err());
+        assert!(read_vec::<u64>(&mut [0u8; 8].as_slice(), usize::MAX).is_err());
+    }
+}
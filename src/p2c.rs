@@ -2,76 +2,598 @@
 
 use itertools::Itertools;
 use klickhouse::IndexMap;
+use polars::export::arrow::{
+    array::{Array, FixedSizeBinaryArray},
+    bitmap::Bitmap,
+    datatypes::ArrowDataType,
+};
 use polars::prelude::*;
+use rayon::prelude::*;
 
 use super::{ClickhouseType, Error};
+use crate::{arrow, c2p, native, table::ValueMap};
 
-pub(crate) struct BlockIterator<'a> {
-    info: klickhouse::block::BlockInfo,
-    column_types: IndexMap<String, klickhouse::Type>,
-    iters:
-        IndexMap<String, Box<dyn ExactSizeIterator<Item = klickhouse::Value> + Send + Sync + 'a>>,
-}
+/// Default maximal number of rows per inserted block.
+const BLOCK_ROWS: usize = 200_000;
 
-impl<'a> Iterator for BlockIterator<'a> {
-    type Item = klickhouse::block::Block;
-    fn next(&mut self) -> Option<Self::Item> {
-        let column_data: IndexMap<String, Vec<klickhouse::Value>> = self
-            .iters
-            .iter_mut()
-            .map(|(k, it)| (k.clone(), it.take(200_000).collect_vec()))
-            .collect();
-        let rows = column_data
-            .values()
-            .map(|v| v.len() as u64)
-            .next()
-            .unwrap_or_default();
-        if rows == 0 {
-            return None;
-        }
-        Some(klickhouse::block::Block {
-            info: self.info.clone(),
-            rows,
-            column_types: self.column_types.clone(),
-            column_data,
-        })
-    }
+/// Number of rows per inserted block, so that blocks have at most `max_rows` rows and, when given,
+/// about `target_bytes` bytes according to the memory footprint of the dataframe.
+pub(crate) fn block_rows(
+    df: &DataFrame,
+    max_rows: Option<usize>,
+    target_bytes: Option<usize>,
+) -> usize {
+    let max_rows = max_rows.unwrap_or(BLOCK_ROWS).max(1);
+    let Some(target_bytes) = target_bytes else {
+        return max_rows;
+    };
+    let row_bytes = df.estimated_size().div_ceil(df.height().max(1)).max(1);
+    (target_bytes / row_bytes).clamp(1, max_rows)
 }
-pub(crate) struct BlockIntoIterator {
+
+/// Number of rows of a block, and its columns with their types.
+type BlockSeries<'a> = (usize, Vec<(Series, &'a ClickhouseType)>);
+
+/// Dataframe to insert, split into blocks of `rows` rows.
+///
+/// The blocks are converted lazily and own their data, so that they can be streamed to the client
+/// within a single `INSERT` query.
+#[derive(Clone)]
+pub(crate) struct Blocks {
     pub(crate) df: DataFrame,
     pub(crate) cols: IndexMap<String, ClickhouseType>,
+    /// Constant values for the table columns missing from the dataframe.
+    pub(crate) defaults: ValueMap,
+    pub(crate) decimal_rounding: DecimalRounding,
+    /// Number of rows per block, see [block_rows].
+    pub(crate) rows: usize,
 }
-impl BlockIntoIterator {
-    pub(crate) fn try_into_iter(&self) -> Result<BlockIterator, Error> {
-        let info = klickhouse::block::BlockInfo {
-            is_overflows: false,
-            bucket_num: 0,
+impl Blocks {
+    /// Row ranges `(offset, length)` of the blocks, with at most `rows` rows each.
+    ///
+    /// When the columns share the same chunks, consecutive chunks are grouped so that block
+    /// boundaries fall on chunk boundaries where possible. Blocks are zero-copy slices either way.
+    fn ranges(&self) -> Vec<(usize, usize)> {
+        let chunk_lengths: Vec<usize> = match self.df.get_columns().first() {
+            Some(col) if !self.df.should_rechunk() => col.chunk_lengths().collect(),
+            _ => vec![self.df.height()],
+        };
+        let mut ranges = vec![];
+        let (mut start, mut end) = (0, 0);
+        for len in chunk_lengths {
+            if end > start && end - start + len > self.rows {
+                ranges.push((start, end - start));
+                start = end;
+            }
+            end += len;
+            while end - start > self.rows {
+                ranges.push((start, self.rows));
+                start += self.rows;
+            }
+        }
+        if end > start {
+            ranges.push((start, end - start));
+        }
+        ranges
+    }
+    /// Block with the given row range, with its number of rows.
+    fn value_block(
+        &self,
+        (offset, len): (usize, usize),
+    ) -> Result<(usize, klickhouse::block::Block), Error> {
+        let df = self.df.slice(offset as i64, len);
+        let rows = df.height();
+        // Columns are converted in parallel.
+        let column_data: Vec<(String, Vec<klickhouse::Value>)> = self
+            .cols
+            .iter()
+            .collect_vec()
+            .into_par_iter()
+            .map(|(col, type_)| -> Result<_, Error> {
+                let values = match (df.column(col), self.defaults.get(col)) {
+                    (Ok(series), _) => {
+                        series_to_values(series, type_.clone(), self.decimal_rounding)?
+                            .collect_vec()
+                    }
+                    (Err(_), Some(value)) => vec![value.clone(); rows],
+                    (Err(e), None) => return Err(e.into()),
+                };
+                Ok((col.clone(), values))
+            })
+            .collect::<Result<_, Error>>()?;
+        let block = klickhouse::block::Block {
+            info: klickhouse::block::BlockInfo {
+                is_overflows: false,
+                bucket_num: 0,
+            },
+            rows: rows as u64,
+            column_types: self
+                .cols
+                .iter()
+                .map(|(col, type_)| (col.clone(), type_.clone().into()))
+                .collect(),
+            column_data: column_data.into_iter().collect(),
         };
-        // This covers all columns.
-        let column_types: IndexMap<String, klickhouse::Type> = self
+        Ok((rows, block))
+    }
+    /// Columns of the block with the given row range, including the defaults.
+    fn block_series(&self, (offset, len): (usize, usize)) -> Result<BlockSeries, Error> {
+        let df = self.df.slice(offset as i64, len);
+        let columns = self
             .cols
-            .clone()
-            .into_iter()
-            .map(|(col, type_)| (col, type_.into()))
-            .collect();
-        // This only contains the columns from the dataframe, not the defaults.
-        let iters: IndexMap<String, _> = self
-            .df
-            .get_columns()
             .iter()
-            .map(|col| -> Result<_, Error> {
-                let values = series_to_values(col, self.cols.get(col.name()).unwrap().clone())?;
-                Ok((col.name().to_string(), values))
+            .map(|(col, type_)| -> Result<_, Error> {
+                let series = match (df.column(col), self.defaults.get(col)) {
+                    (Ok(series), _) => series.clone(),
+                    (Err(_), Some(value)) => {
+                        let values = vec![value.clone(); df.height()];
+                        let mut series = c2p::values_to_series(values, type_.clone(), true)?;
+                        series.rename(col);
+                        series
+                    }
+                    (Err(e), None) => return Err(e.into()),
+                };
+                Ok((series, type_))
+            })
+            .try_collect()?;
+        Ok((df.height(), columns))
+    }
+    /// Block of the Native format with the given row range, with its number of rows.
+    fn native_block(&self, range: (usize, usize)) -> Result<(usize, Vec<u8>), Error> {
+        let (rows, columns) = self.block_series(range)?;
+        let mut out = vec![];
+        native::write_block(
+            &mut out,
+            &columns.iter().map(|(s, t)| (s, *t)).collect_vec(),
+            self.decimal_rounding,
+        )?;
+        Ok((rows, out))
+    }
+    /// Record batch of an Arrow stream with the given row range, with its number of rows.
+    ///
+    /// The series are converted to the Arrow types of the Clickhouse types, see [arrow_column].
+    fn arrow_block(
+        &self,
+        range: (usize, usize),
+        encoder: &mut arrow::StreamEncoder,
+    ) -> Result<(usize, Vec<u8>), Error> {
+        let (rows, columns) = self.block_series(range)?;
+        let columns = columns
+            .into_iter()
+            .map(|(series, type_)| -> Result<_, Error> {
+                let array = arrow_column(&series, type_, self.decimal_rounding)?;
+                Ok((series.name().to_string(), array))
             })
             .try_collect()?;
-        Ok(BlockIterator {
-            info,
-            column_types,
-            iters,
+        Ok((rows, encoder.write(columns)?))
+    }
+    pub(crate) fn into_value_blocks(
+        self,
+    ) -> impl Iterator<Item = Result<(usize, klickhouse::block::Block), Error>> + Send + Sync {
+        self.ranges()
+            .into_iter()
+            .map(move |range| self.value_block(range))
+    }
+    /// Encode the dataframe into blocks of the Native format.
+    pub(crate) fn into_native_blocks(
+        self,
+    ) -> impl Iterator<Item = Result<(usize, Vec<u8>), Error>> + Send + Sync {
+        self.ranges()
+            .into_iter()
+            .map(move |range| self.native_block(range))
+    }
+    /// Encode the dataframe into an Arrow IPC stream, one record batch per block, followed by the
+    /// end of the stream.
+    pub(crate) fn into_arrow_blocks(
+        self,
+    ) -> impl Iterator<Item = Result<(usize, Vec<u8>), Error>> + Send + Sync {
+        let mut encoder = arrow::StreamEncoder::default();
+        let mut ranges = self.ranges().into_iter();
+        std::iter::from_fn(move || match ranges.next() {
+            Some(range) => Some(self.arrow_block(range, &mut encoder)),
+            None if !encoder.finished => Some(encoder.finish().map(|end| (0, end))),
+            None => None,
         })
     }
 }
 
+/// Convert a series to the Arrow type that Clickhouse reads into the given type, following its
+/// Arrow input format: `DateTime` as `UInt32` seconds, `Date` as `UInt16` days, decimals as Arrow
+/// decimals, scaled as with the Native format, and UUIDs as 16-byte fixed-size binaries. Other
+/// series are cast to the representation of the type, and converted further by the server.
+fn arrow_column(
+    series: &Series,
+    type_: &ClickhouseType,
+    rounding: DecimalRounding,
+) -> Result<Box<dyn Array>, Error> {
+    use klickhouse::Type as T;
+    let native = match type_ {
+        ClickhouseType::Nullable(inner) => return arrow_column(series, inner, rounding),
+        ClickhouseType::Native(T::Nullable(inner)) => {
+            return arrow_column(series, &ClickhouseType::from(*inner.clone()), rounding)
+        }
+        ClickhouseType::Native(T::Uuid) | ClickhouseType::BinaryUuid => {
+            let uuids = uuid_values(series)?;
+            let validity = Bitmap::from_iter(uuids.iter().map(Option::is_some));
+            let values: Vec<u8> = uuids
+                .into_iter()
+                .flat_map(|x| x.unwrap_or_default().to_be_bytes())
+                .collect();
+            return Ok(FixedSizeBinaryArray::new(
+                ArrowDataType::FixedSizeBinary(16),
+                values.into(),
+                (validity.unset_bits() > 0).then_some(validity),
+            )
+            .boxed());
+        }
+        ClickhouseType::Native(native) => native,
+        _ => return Ok(arrow_array(&series.cast(&DataType::try_from(type_)?)?)),
+    };
+    let series = match native {
+        T::DateTime(_) => datetime_seconds(series, type_)?
+            .into_iter()
+            .collect::<UInt32Chunked>()
+            .into_series(),
+        T::Date => date_days(series, type_)?
+            .into_iter()
+            .collect::<UInt16Chunked>()
+            .into_series(),
+        T::Decimal32(_) | T::Decimal64(_) | T::Decimal128(_) => {
+            let (precision, scale) = decimal_parameters(native).unwrap();
+            decimal_values(series, type_, rounding)?
+                .into_iter()
+                .collect::<Int128Chunked>()
+                .into_decimal(Some(precision as usize), scale)?
+                .into_series()
+        }
+        _ => series.cast(&DataType::try_from(type_)?)?,
+    };
+    Ok(arrow_array(&series))
+}
+
+fn arrow_array(series: &Series) -> Box<dyn Array> {
+    series.rechunk().to_arrow(0, false)
+}
+
+/// Handling of NaN and infinite values in float columns on insertion, including the elements of
+/// `Array` columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonFinitePolicy {
+    /// Insert the values as they are.
+    #[default]
+    Keep,
+    /// Replace the values by `NULL`. Values in non-nullable columns (or arrays of non-nullable
+    /// elements) are rejected as with [NonFinitePolicy::Reject].
+    Null,
+    /// Fail with [Error::NonFiniteValue], reporting the column and the row index.
+    Reject,
+}
+
+/// Rounding when inserting float series into `Decimal` columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DecimalRounding {
+    /// Round half away from zero.
+    #[default]
+    Nearest,
+    TowardZero,
+    Down,
+    Up,
+}
+/// Casting of series to the data type expected for their column on insertion.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Coercion {
+    /// Series must already have the expected data type.
+    Disabled,
+    /// Only perform casts that preserve all values, e.g. `Int32` to `Int64`.
+    #[default]
+    Widening,
+    /// Perform any cast supported by Polars. Values that cannot be represented become null.
+    Lossy,
+}
+
+/// Signedness and width of integer data types.
+fn integer_bits(dtype: &DataType) -> Option<(bool, u32)> {
+    Some(match dtype {
+        DataType::Int8 => (true, 8),
+        DataType::Int16 => (true, 16),
+        DataType::Int32 => (true, 32),
+        DataType::Int64 => (true, 64),
+        DataType::UInt8 => (false, 8),
+        DataType::UInt16 => (false, 16),
+        DataType::UInt32 => (false, 32),
+        DataType::UInt64 => (false, 64),
+        _ => return None,
+    })
+}
+
+/// Whether casting from one data type to another preserves all values.
+fn is_widening(from: &DataType, to: &DataType) -> bool {
+    match (from, to) {
+        (DataType::Null, _) => true,
+        (DataType::List(from), DataType::List(to)) => is_widening(from, to),
+        (DataType::String, DataType::Categorical(..)) => true,
+        (DataType::Float32, DataType::Float64) => true,
+        _ => match (integer_bits(from), integer_bits(to), to) {
+            (Some((signed_from, bits_from)), Some((signed_to, bits_to)), _) => {
+                if signed_from == signed_to {
+                    bits_from <= bits_to
+                } else {
+                    !signed_from && bits_from < bits_to
+                }
+            }
+            // Integers exactly representable by the mantissa
+            (Some((_, bits)), None, DataType::Float32) => bits <= 16,
+            (Some((_, bits)), None, DataType::Float64) => bits <= 32,
+            _ => from == to,
+        },
+    }
+}
+
+/// Cast a series to the data type expected for insertion into a column of the given type,
+/// according to the [Coercion] mode. Series that cannot be cast are returned unchanged.
+pub(crate) fn coerce(
+    series: Series,
+    type_: &ClickhouseType,
+    coercion: Coercion,
+) -> Result<Series, Error> {
+    let Err(Some(target)) = check_insertable(series.dtype(), type_) else {
+        return Ok(series);
+    };
+    Ok(match coercion {
+        Coercion::Widening if is_widening(series.dtype(), &target) => {
+            series.strict_cast(&target)?
+        }
+        Coercion::Lossy => series.cast(&target)?,
+        _ => series,
+    })
+}
+
+/// Apply a [NonFinitePolicy] to a series, including the floats of list series. Other series are
+/// returned unchanged.
+///
+/// Non-finite values are rejected rather than replaced by nulls if the column (or the elements of
+/// the arrays) is not nullable.
+pub(crate) fn handle_non_finite(
+    series: Series,
+    policy: NonFinitePolicy,
+    type_: &ClickhouseType,
+) -> Result<Series, Error> {
+    if policy == NonFinitePolicy::Keep || !has_floats(series.dtype()) {
+        return Ok(series);
+    }
+    if let DataType::List(_) = series.dtype() {
+        return handle_non_finite_lists(series, policy, type_);
+    }
+    let non_finite: BooleanChunked = series
+        .is_finite()?
+        .into_iter()
+        .map(|x| x == Some(false))
+        .collect();
+    if let Some(row) = non_finite.into_iter().position(|x| x == Some(true)) {
+        if policy == NonFinitePolicy::Reject || !type_.is_nullable() {
+            return Err(Error::NonFiniteValue(series.name().into(), row));
+        }
+    } else {
+        return Ok(series);
+    }
+    let mut out = match series.dtype() {
+        DataType::Float32 => series.f32()?.set(&non_finite, None)?.into_series(),
+        _ => series.f64()?.set(&non_finite, None)?.into_series(),
+    };
+    out.rename(series.name());
+    Ok(out)
+}
+
+fn has_floats(dtype: &DataType) -> bool {
+    match dtype {
+        DataType::List(inner) => has_floats(inner),
+        dtype => dtype.is_float(),
+    }
+}
+
+/// Apply a [NonFinitePolicy] to the elements of a list series, for an `Array` column. Errors
+/// report the row of the list.
+fn handle_non_finite_lists(
+    series: Series,
+    policy: NonFinitePolicy,
+    type_: &ClickhouseType,
+) -> Result<Series, Error> {
+    let ClickhouseType::Native(klickhouse::Type::Array(inner)) =
+        ClickhouseType::from(klickhouse::Type::from(type_.clone()).strip_null().clone())
+    else {
+        // Reported when checking the schema.
+        return Ok(series);
+    };
+    let inner = ClickhouseType::from(*inner);
+    let ca = series.list()?;
+    // Rows are only handled one by one when some values are not finite.
+    let values = ca.get_inner();
+    if values.dtype().is_float() && values.is_finite()?.into_iter().all(|x| x != Some(false)) {
+        return Ok(series);
+    }
+    let rows: ListChunked = ca
+        .into_iter()
+        .enumerate()
+        .map(|(row, values)| {
+            values
+                .map(|values| handle_non_finite(values, policy, &inner))
+                .transpose()
+                .map_err(|e| match e {
+                    Error::NonFiniteValue(_, _) => Error::NonFiniteValue(series.name().into(), row),
+                    e => e,
+                })
+        })
+        .collect::<Result<_, _>>()?;
+    let mut out = rows.into_series().cast(series.dtype())?;
+    out.rename(series.name());
+    Ok(out)
+}
+
+/// Maximal precision and scale of a (nullable) decimal type.
+fn decimal_parameters(type_: &klickhouse::Type) -> Option<(u32, usize)> {
+    match type_.strip_null() {
+        klickhouse::Type::Decimal32(scale) => Some((9, *scale)),
+        klickhouse::Type::Decimal64(scale) => Some((18, *scale)),
+        klickhouse::Type::Decimal128(scale) => Some((38, *scale)),
+        _ => None,
+    }
+}
+
+/// Scale and round a float series into the integer representation of a `Decimal` column.
+///
+/// The values are scaled from their shortest decimal representation rather than multiplied in
+/// floating point, so that e.g. `1.005` is exactly `100.5` hundredths. Values with more digits
+/// than the precision of the column fail with [Error::DecimalOverflow].
+pub(crate) fn decimal_values(
+    series: &Series,
+    type_: &ClickhouseType,
+    rounding: DecimalRounding,
+) -> Result<Vec<Option<i128>>, Error> {
+    let (precision, scale) = decimal_parameters(&type_.clone().into())
+        .ok_or_else(|| Error::UnsupportedClickhouseType(type_.clone()))?;
+    let bound = 10i128.pow(precision);
+    let convert = |row: usize, x: f64, repr: String| -> Result<i128, Error> {
+        if !x.is_finite() {
+            return Err(Error::NonFiniteValue(series.name().into(), row));
+        }
+        scale_decimal(&repr, x < 0.0, scale, rounding)
+            .filter(|x| x.abs() < bound)
+            .ok_or_else(|| Error::DecimalOverflow(x, type_.clone()))
+    };
+    match series.dtype() {
+        DataType::Float32 => series
+            .f32()?
+            .into_iter()
+            .enumerate()
+            .map(|(row, x)| {
+                x.map(|x| convert(row, x.into(), format!("{:e}", x.abs())))
+                    .transpose()
+            })
+            .collect(),
+        DataType::Float64 => series
+            .f64()?
+            .into_iter()
+            .enumerate()
+            .map(|(row, x)| {
+                x.map(|x| convert(row, x, format!("{:e}", x.abs())))
+                    .transpose()
+            })
+            .collect(),
+        dtype => Err(Error::MismatchingSeriesType(dtype.clone())),
+    }
+}
+
+/// Scale the absolute value of a float, given in scientific notation (e.g. `1.005e0`), by
+/// `10^scale` and round it. Returns `None` if the result does not fit in an [i128].
+fn scale_decimal(
+    repr: &str,
+    negative: bool,
+    scale: usize,
+    rounding: DecimalRounding,
+) -> Option<i128> {
+    let (mantissa, exponent) = repr.split_once('e')?;
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: u128 = format!("{}{}", int, frac).parse().ok()?;
+    let shift = exponent.parse::<i64>().ok()? + scale as i64 - frac.len() as i64;
+    let magnitude = if shift >= 0 {
+        digits.checked_mul(10u128.checked_pow(u32::try_from(shift).ok()?)?)?
+    } else {
+        // Quotient and remainder of the division by `10^-shift`, and whether the remainder is
+        // at least half of it.
+        let (quotient, remainder, half) = match u32::try_from(-shift)
+            .ok()
+            .and_then(|shift| 10u128.checked_pow(shift))
+        {
+            Some(divisor) => {
+                let remainder = digits % divisor;
+                (digits / divisor, remainder, remainder * 2 >= divisor)
+            }
+            None => (0, digits, false),
+        };
+        let round_up = match rounding {
+            DecimalRounding::Nearest => half,
+            DecimalRounding::TowardZero => false,
+            DecimalRounding::Down => negative && remainder > 0,
+            DecimalRounding::Up => !negative && remainder > 0,
+        };
+        quotient + u128::from(round_up)
+    };
+    let magnitude = i128::try_from(magnitude).ok()?;
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// UUIDs of a string series (in their textual representation) or binary series (as their 16 bytes).
+pub(crate) fn uuid_values(series: &Series) -> Result<Vec<Option<u128>>, Error> {
+    let invalid = |x: &dyn std::fmt::Debug| Error::InvalidUuid(format!("{:?}", x));
+    match series.dtype() {
+        DataType::String => series
+            .str()?
+            .into_iter()
+            .map(|x| {
+                x.map(|x| {
+                    klickhouse::Uuid::parse_str(x)
+                        .map(|x| x.as_u128())
+                        .map_err(|_| invalid(&x))
+                })
+                .transpose()
+            })
+            .collect(),
+        DataType::Binary => series
+            .binary()?
+            .into_iter()
+            .map(|x| {
+                x.map(|x| {
+                    klickhouse::Uuid::from_slice(x)
+                        .map(|x| x.as_u128())
+                        .map_err(|_| invalid(&x))
+                })
+                .transpose()
+            })
+            .collect(),
+        dtype => Err(Error::MismatchingSeriesType(dtype.clone())),
+    }
+}
+
+/// Seconds since the epoch of a datetime series, for a `DateTime` column.
+pub(crate) fn datetime_seconds(
+    series: &Series,
+    type_: &ClickhouseType,
+) -> Result<Vec<Option<u32>>, Error> {
+    let ca = series
+        .datetime()
+        .map_err(|_| Error::MismatchingSeriesType(series.dtype().clone()))?;
+    let per_second = match ca.time_unit() {
+        TimeUnit::Nanoseconds => 1_000_000_000,
+        TimeUnit::Microseconds => 1_000_000,
+        TimeUnit::Milliseconds => 1_000,
+    };
+    ca.into_iter()
+        .map(|x| {
+            x.map(|x| {
+                let seconds = x.div_euclid(per_second);
+                u32::try_from(seconds).map_err(|_| Error::DateTimeOverflow(seconds, type_.clone()))
+            })
+            .transpose()
+        })
+        .collect()
+}
+
+/// Days since the epoch of a date series, for a `Date` column.
+pub(crate) fn date_days(
+    series: &Series,
+    type_: &ClickhouseType,
+) -> Result<Vec<Option<u16>>, Error> {
+    let ca = series
+        .date()
+        .map_err(|_| Error::MismatchingSeriesType(series.dtype().clone()))?;
+    ca.into_iter()
+        .map(|x| {
+            x.map(|x| u16::try_from(x).map_err(|_| Error::DateOverflow(x, type_.clone())))
+                .transpose()
+        })
+        .collect()
+}
+
 impl TryFrom<&DataType> for ClickhouseType {
     type Error = Error;
     fn try_from(source: &DataType) -> Result<Self, Self::Error> {
@@ -93,6 +615,13 @@ impl TryFrom<&DataType> for ClickhouseType {
 
             DataType::Boolean => Self::Bool,
 
+            DataType::Date => Self::Native(klickhouse::Type::Date),
+            // Truncated to seconds on insertion.
+            DataType::Datetime(_, tz) => match tz {
+                Some(tz) => format!("DateTime('{}')", tz).parse()?,
+                None => "DateTime".parse()?,
+            },
+
             DataType::Categorical(_, _) => Self::Native(klickhouse::Type::LowCardinality(
                 Box::new(klickhouse::Type::String),
             )),
@@ -106,6 +635,101 @@ impl TryFrom<&DataType> for ClickhouseType {
     }
 }
 
+/// Check that a series with the given data type can be converted by [series_to_values].
+/// On failure, returns the data type the series can be cast to, if any.
+pub(crate) fn check_insertable(
+    dtype: &DataType,
+    type_: &ClickhouseType,
+) -> Result<(), Option<DataType>> {
+    use klickhouse::Type as T;
+    match type_ {
+        ClickhouseType::Nullable(inner) => check_insertable(dtype, inner),
+        ClickhouseType::Native(T::Nullable(inner)) => {
+            check_insertable(dtype, &ClickhouseType::from(*inner.clone()))
+        }
+
+        ClickhouseType::Native(T::Decimal32(_) | T::Decimal64(_) | T::Decimal128(_)) => {
+            if dtype.is_float() {
+                Ok(())
+            } else {
+                Err(Some(DataType::Float64))
+            }
+        }
+
+        ClickhouseType::Native(T::Uuid) | ClickhouseType::BinaryUuid => {
+            if matches!(dtype, DataType::String | DataType::Binary) {
+                Ok(())
+            } else {
+                Err(Some(DataType::String))
+            }
+        }
+
+        ClickhouseType::Native(T::DateTime(tz)) => {
+            if matches!(dtype, DataType::Datetime(..)) {
+                Ok(())
+            } else {
+                Err(Some(DataType::Datetime(
+                    TimeUnit::Milliseconds,
+                    Some(tz.name().into()),
+                )))
+            }
+        }
+
+        ClickhouseType::Native(T::Date) => {
+            if dtype == &DataType::Date {
+                Ok(())
+            } else {
+                Err(Some(DataType::Date))
+            }
+        }
+
+        ClickhouseType::Native(T::LowCardinality(s)) if s.strip_null() == &T::String => {
+            if matches!(dtype, DataType::Categorical(..)) {
+                Ok(())
+            } else {
+                Err(Some(DataType::Categorical(None, Default::default())))
+            }
+        }
+
+        ClickhouseType::Native(T::Array(inner)) => {
+            let inner = ClickhouseType::from(*inner.clone());
+            match dtype {
+                DataType::List(dtype) => check_insertable(dtype, &inner)
+                    .map_err(|cast| cast.map(|cast| DataType::List(Box::new(cast)))),
+                // Suggest a list of the data type expected for the values, if any.
+                _ => match check_insertable(&DataType::Null, &inner) {
+                    Err(Some(cast)) => Err(Some(DataType::List(Box::new(cast)))),
+                    _ => Err(None),
+                },
+            }
+        }
+
+        ClickhouseType::Bool
+        | ClickhouseType::Native(
+            T::String
+            | T::UInt8
+            | T::UInt16
+            | T::UInt32
+            | T::UInt64
+            | T::Int8
+            | T::Int16
+            | T::Int32
+            | T::Int64
+            | T::Float32
+            | T::Float64,
+        ) => {
+            let expected = DataType::try_from(type_).map_err(|_| None)?;
+            if dtype == &expected {
+                Ok(())
+            } else {
+                Err(Some(expected))
+            }
+        }
+
+        _ => Err(None),
+    }
+}
+
 macro_rules! extract_vals {
     ($series:ident, $t: ident, $f: ident) => {
         Box::new(
@@ -120,10 +744,25 @@ macro_rules! extract_vals {
         )
     };
 }
+macro_rules! extract_decimal {
+    ($series:ident, $t: ident, $int: ty, $type_: ident, $scale: ident, $rounding: ident) => {{
+        let values: Vec<klickhouse::Value> = decimal_values($series, &$type_, $rounding)?
+            .into_iter()
+            // The values are within the precision of the type.
+            .map(|x| {
+                x.map_or(klickhouse::Value::Null, |x| {
+                    klickhouse::Value::$t($scale, x as $int)
+                })
+            })
+            .collect();
+        Box::new(values.into_iter())
+    }};
+}
 /// Convert a polars [Series] into an iterator of [klickhouse::Value].
 pub(crate) fn series_to_values<'a>(
     series: &'a Series,
     type_: ClickhouseType,
+    decimal_rounding: DecimalRounding,
 ) -> Result<Box<dyn ExactSizeIterator<Item = klickhouse::Value> + Send + Sync + 'a>, Error> {
     Ok(match type_ {
         ClickhouseType::Native(klickhouse::Type::String) => {
@@ -163,16 +802,63 @@ pub(crate) fn series_to_values<'a>(
 
         ClickhouseType::Bool => extract_vals!(series, UInt8, bool),
 
+        ClickhouseType::Native(klickhouse::Type::Uuid) | ClickhouseType::BinaryUuid => {
+            let values: Vec<klickhouse::Value> = uuid_values(series)?
+                .into_iter()
+                .map(|x| match x {
+                    Some(x) => klickhouse::Value::Uuid(klickhouse::Uuid::from_u128(x)),
+                    None => klickhouse::Value::Null,
+                })
+                .collect();
+            Box::new(values.into_iter())
+        }
+
+        ClickhouseType::Native(klickhouse::Type::DateTime(tz)) => {
+            let values: Vec<klickhouse::Value> = datetime_seconds(series, &type_)?
+                .into_iter()
+                .map(|x| match x {
+                    Some(x) => klickhouse::Value::DateTime(klickhouse::DateTime(tz, x)),
+                    None => klickhouse::Value::Null,
+                })
+                .collect();
+            Box::new(values.into_iter())
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Date) => {
+            let values: Vec<klickhouse::Value> = date_days(series, &type_)?
+                .into_iter()
+                .map(|x| match x {
+                    Some(x) => klickhouse::Value::Date(klickhouse::Date(x)),
+                    None => klickhouse::Value::Null,
+                })
+                .collect();
+            Box::new(values.into_iter())
+        }
+
+        ClickhouseType::Native(klickhouse::Type::Decimal32(scale)) => {
+            extract_decimal!(series, Decimal32, i32, type_, scale, decimal_rounding)
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal64(scale)) => {
+            extract_decimal!(series, Decimal64, i64, type_, scale, decimal_rounding)
+        }
+        ClickhouseType::Native(klickhouse::Type::Decimal128(scale)) => {
+            extract_decimal!(series, Decimal128, i128, type_, scale, decimal_rounding)
+        }
+
         ClickhouseType::Native(klickhouse::Type::LowCardinality(s))
-            if s.as_ref() == &klickhouse::Type::String =>
+            if s.strip_null() == &klickhouse::Type::String =>
         {
-            Box::new(
-                series
-                    .categorical()
-                    .unwrap()
-                    .iter_str()
-                    .map(|x| klickhouse::Value::String(x.unwrap().into())),
-            )
+            let nullable = matches!(s.as_ref(), klickhouse::Type::Nullable(_));
+            let ca = series
+                .categorical()
+                .map_err(|_| Error::MismatchingSeriesType(series.dtype().clone()))?;
+            if !nullable && ca.null_count() > 0 {
+                return Err(Error::UnexpectedNull("In LowCardinality(String)"));
+            }
+            Box::new(ca.iter_str().map(|x| match x {
+                Some(x) => klickhouse::Value::String(x.into()),
+                None => klickhouse::Value::Null,
+            }))
         }
 
         ClickhouseType::Native(klickhouse::Type::Array(type_)) => {
@@ -183,10 +869,14 @@ pub(crate) fn series_to_values<'a>(
                     .into_iter()
                     .map(move |v| match v {
                         Some(v) => klickhouse::Value::Array(
-                            series_to_values(&v, ClickhouseType::from(*type_.clone()))
-                                // TODO: Handle the error without allocating
-                                .unwrap()
-                                .collect(),
+                            series_to_values(
+                                &v,
+                                ClickhouseType::from(*type_.clone()),
+                                decimal_rounding,
+                            )
+                            // TODO: Handle the error without allocating
+                            .unwrap()
+                            .collect(),
                         ),
                         None => klickhouse::Value::Null,
                     }),
@@ -195,12 +885,216 @@ pub(crate) fn series_to_values<'a>(
 
         //Nulls
         ClickhouseType::Native(klickhouse::Type::Nullable(s)) => {
-            series_to_values(series, ClickhouseType::from(*s))?
+            series_to_values(series, ClickhouseType::from(*s), decimal_rounding)?
         }
-        ClickhouseType::Nullable(type_) => series_to_values(series, *type_)?,
+        ClickhouseType::Nullable(type_) => series_to_values(series, *type_, decimal_rounding)?,
 
         _ => {
             return Err(Error::UnsupportedClickhouseType(type_.clone()));
         }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn block_sizes() -> anyhow::Result<()> {
+        let df = df!("a" => vec![1i64; 1000], "b" => vec![1.0f64; 1000])?;
+        assert_eq!(block_rows(&df, None, None), BLOCK_ROWS);
+        assert_eq!(block_rows(&df, Some(100), None), 100);
+        assert_eq!(block_rows(&df, None, Some(1600)), 100);
+        assert_eq!(block_rows(&df, Some(50), Some(1600)), 50);
+        assert_eq!(block_rows(&df, None, Some(1)), 1);
+        Ok(())
+    }
+    #[test]
+    fn block_ranges() -> anyhow::Result<()> {
+        let blocks = |df: DataFrame, rows| Blocks {
+            df,
+            cols: Default::default(),
+            defaults: Default::default(),
+            decimal_rounding: Default::default(),
+            rows,
+        };
+        let chunk = |n: usize| df!("a" => vec![1i64; n], "b" => vec![1.0f64; n]);
+        let mut df = chunk(3)?;
+        for n in [4, 2, 10, 1] {
+            df.vstack_mut(&chunk(n)?)?;
+        }
+        assert_eq!(df.n_chunks(), 5);
+        assert_eq!(
+            blocks(df.clone(), 7).ranges(),
+            vec![(0, 7), (7, 2), (9, 7), (16, 4)]
+        );
+        assert_eq!(blocks(df.clone(), 100).ranges(), vec![(0, 20)]);
+        // Misaligned chunks
+        let df = DataFrame::new(vec![df.column("a")?.clone(), df.column("b")?.rechunk()])?;
+        assert!(df.should_rechunk());
+        assert_eq!(blocks(df, 8).ranges(), vec![(0, 8), (8, 8), (16, 4)]);
+        Ok(())
+    }
+    #[test]
+    fn non_finite() -> anyhow::Result<()> {
+        let series = Series::new("x", &[Some(1.0), Some(f64::NAN), None, Some(f64::INFINITY)]);
+        let float64: ClickhouseType = "Float64".parse()?;
+        let nullable = float64.clone().nullable();
+        assert_eq!(
+            handle_non_finite(series.clone(), NonFinitePolicy::Keep, &float64)?.null_count(),
+            1
+        );
+        let nulls = handle_non_finite(series.clone(), NonFinitePolicy::Null, &nullable)?;
+        assert_eq!(nulls.name(), "x");
+        assert_eq!(nulls.null_count(), 3);
+        assert!(matches!(
+            handle_non_finite(series.clone(), NonFinitePolicy::Reject, &nullable),
+            Err(Error::NonFiniteValue(col, 1)) if col == "x"
+        ));
+        assert!(matches!(
+            handle_non_finite(series, NonFinitePolicy::Null, &float64),
+            Err(Error::NonFiniteValue(col, 1)) if col == "x"
+        ));
+
+        let lists = Series::new(
+            "l",
+            [
+                Series::new("", [1.0f64]),
+                Series::new("", [2.0f64, f64::NAN]),
+            ],
+        );
+        let array: ClickhouseType = "Array(Float64)".parse()?;
+        let nullable_array: ClickhouseType = "Array(Nullable(Float64))".parse()?;
+        assert!(matches!(
+            handle_non_finite(lists.clone(), NonFinitePolicy::Null, &array),
+            Err(Error::NonFiniteValue(col, 1)) if col == "l"
+        ));
+        assert!(matches!(
+            handle_non_finite(lists.clone(), NonFinitePolicy::Reject, &nullable_array),
+            Err(Error::NonFiniteValue(col, 1)) if col == "l"
+        ));
+        let nulls = handle_non_finite(lists.clone(), NonFinitePolicy::Null, &nullable_array)?;
+        assert_eq!(nulls.name(), "l");
+        assert_eq!(nulls.dtype(), lists.dtype());
+        assert_eq!(nulls.list()?.get_as_series(1).unwrap().null_count(), 1);
+        let finite = lists.slice(0, 1);
+        assert!(
+            handle_non_finite(finite.clone(), NonFinitePolicy::Reject, &array)?
+                .equals_missing(&finite)
+        );
+        Ok(())
+    }
+    #[test]
+    fn coercion() -> anyhow::Result<()> {
+        let int64 = ClickhouseType::Native(klickhouse::Type::Int64);
+        let int8 = ClickhouseType::Native(klickhouse::Type::Int8);
+        let series = Series::new("x", &[1i32, 300]);
+        for (type_, coercion, expected) in [
+            (&int64, Coercion::Disabled, DataType::Int32),
+            (&int64, Coercion::Widening, DataType::Int64),
+            (&int8, Coercion::Widening, DataType::Int32),
+            (&int8, Coercion::Lossy, DataType::Int8),
+        ] {
+            let coerced = coerce(series.clone(), type_, coercion)?;
+            assert_eq!(coerced.dtype(), &expected);
+            assert_eq!(coerced.name(), "x");
+        }
+        assert_eq!(coerce(series, &int8, Coercion::Lossy)?.null_count(), 1);
+
+        assert!(is_widening(&DataType::UInt32, &DataType::Int64));
+        assert!(!is_widening(&DataType::UInt32, &DataType::Int32));
+        assert!(!is_widening(&DataType::Int64, &DataType::Float64));
+        Ok(())
+    }
+    #[test]
+    fn decimals() -> anyhow::Result<()> {
+        let series = Series::new("x", &[Some(1.235f64), Some(-1.235), None]);
+        for (rounding, expected) in [
+            (DecimalRounding::Nearest, [124, -124]),
+            (DecimalRounding::TowardZero, [123, -123]),
+            (DecimalRounding::Down, [123, -124]),
+            (DecimalRounding::Up, [124, -123]),
+        ] {
+            assert_eq!(
+                decimal_values(&series, &"Decimal(9, 2)".parse()?, rounding)?,
+                vec![Some(expected[0]), Some(expected[1]), None]
+            );
+        }
+        // Scaled exactly, although `1.005 * 100.0 == 100.49999999999999`.
+        let series = Series::new("x", &[1.005f64, 1e-30, 12345.0]);
+        assert_eq!(
+            decimal_values(&series, &"Decimal(18, 2)".parse()?, Default::default())?,
+            vec![Some(101), Some(0), Some(1234500)]
+        );
+        let series = Series::new("x", &[0.1f32]);
+        assert_eq!(
+            decimal_values(
+                &series,
+                &"Nullable(Decimal(38, 20))".parse()?,
+                Default::default()
+            )?,
+            vec![Some(10i128.pow(19))]
+        );
+        // Out of range for the precision, or for any integer.
+        for (x, type_) in [(1e7, "Decimal(9, 2)"), (1e300, "Decimal(38, 0)")] {
+            assert!(matches!(
+                decimal_values(&Series::new("x", &[x]), &type_.parse()?, Default::default()),
+                Err(Error::DecimalOverflow(..))
+            ));
+        }
+        Ok(())
+    }
+    #[test]
+    fn low_cardinality() -> anyhow::Result<()> {
+        let series = Series::new("a", [Some("x"), None, Some("x")])
+            .cast(&DataType::Categorical(None, Default::default()))?;
+        let nullable: ClickhouseType = "LowCardinality(Nullable(String))".parse()?;
+        check_insertable(series.dtype(), &nullable).unwrap();
+        let values: Vec<_> = series_to_values(&series, nullable, Default::default())?.collect();
+        assert_eq!(
+            values,
+            vec![
+                klickhouse::Value::String("x".into()),
+                klickhouse::Value::Null,
+                klickhouse::Value::String("x".into()),
+            ]
+        );
+        let non_nullable: ClickhouseType = "LowCardinality(String)".parse()?;
+        assert!(matches!(
+            series_to_values(&series, non_nullable, Default::default()),
+            Err(Error::UnexpectedNull(_))
+        ));
+        Ok(())
+    }
+    #[test]
+    fn arrow_uuids() -> anyhow::Result<()> {
+        let uuid = klickhouse::Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8")?;
+        let series = Series::new("id", [Some(uuid.as_bytes().to_vec()), None]);
+        let type_ = ClickhouseType::BinaryUuid.nullable();
+        let array = arrow_column(&series, &type_, Default::default())?;
+        assert_eq!(array.data_type(), &ArrowDataType::FixedSizeBinary(16));
+        let mut encoder = arrow::StreamEncoder::default();
+        let mut stream = encoder.write(vec![("id".into(), array)])?;
+        stream.extend(encoder.finish()?);
+
+        let block = arrow::read_blocks(std::io::Cursor::new(stream))
+            .next()
+            .unwrap()?;
+        assert_eq!(
+            block.column_types["id"],
+            "FixedString(16)".parse::<ClickhouseType>()?.nullable()
+        );
+        assert!(block.column_data["id"].equals_missing(&series));
+        Ok(())
+    }
+    #[test]
+    fn datetimes() -> anyhow::Result<()> {
+        let dtype = DataType::Datetime(TimeUnit::Milliseconds, Some("Europe/Paris".into()));
+        assert_eq!(
+            ClickhouseType::try_from(&dtype)?,
+            "DateTime('Europe/Paris')".parse()?
+        );
+        let dtype = DataType::Datetime(TimeUnit::Microseconds, None);
+        assert_eq!(ClickhouseType::try_from(&dtype)?, "DateTime".parse()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,609 @@
+//! Options of individual queries, passed to the [ClientGeneric](crate::ClientGeneric) methods.
+
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use klickhouse::IndexMap;
+use polars::prelude::*;
+
+use crate::Error;
+
+/// Value of a Clickhouse setting.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SettingValue {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+}
+impl SettingValue {
+    /// SQL literal, for `SETTINGS` clauses.
+    pub(crate) fn sql(&self) -> String {
+        match self {
+            SettingValue::String(s) => {
+                format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+/// Raw value, for URL parameters.
+impl std::fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SettingValue::Bool(x) => write!(f, "{}", u8::from(*x)),
+            SettingValue::Int(x) => write!(f, "{}", x),
+            SettingValue::UInt(x) => write!(f, "{}", x),
+            SettingValue::Float(x) => write!(f, "{}", x),
+            SettingValue::String(x) => write!(f, "{}", x),
+        }
+    }
+}
+macro_rules! setting_value {
+    ($($t: ty => $variant: ident),*) => {
+        $(
+            impl From<$t> for SettingValue {
+                fn from(x: $t) -> Self {
+                    Self::$variant(x.into())
+                }
+            }
+        )*
+    };
+}
+setting_value!(
+    bool => Bool,
+    i8 => Int, i16 => Int, i32 => Int, i64 => Int,
+    u8 => UInt, u16 => UInt, u32 => UInt, u64 => UInt,
+    f32 => Float, f64 => Float,
+    &str => String, String => String
+);
+
+/// Clickhouse settings, e.g. `max_threads` or `join_use_nulls`.
+pub type Settings = IndexMap<String, SettingValue>;
+
+/// Value of a query parameter, bound to `{name:Type}` placeholders.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    String(String),
+    /// Array, e.g. from a [Series].
+    Array(Vec<ParamValue>),
+}
+impl ParamValue {
+    /// SQL literal.
+    pub(crate) fn sql(&self) -> String {
+        match self {
+            ParamValue::Null => "NULL".into(),
+            ParamValue::Bool(x) => x.to_string(),
+            ParamValue::Int(x) => x.to_string(),
+            ParamValue::UInt(x) => x.to_string(),
+            ParamValue::Float(x) => x.to_string(),
+            ParamValue::String(s) => SettingValue::String(s.clone()).sql(),
+            ParamValue::Array(values) => {
+                format!(
+                    "[{}]",
+                    values
+                        .iter()
+                        .map(|v| v.sql())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        }
+    }
+    /// Value in the escaped text format, for the `param_*` URL parameters and the parameters of the
+    /// native protocol.
+    pub(crate) fn text(&self) -> String {
+        match self {
+            ParamValue::Null => "\\N".into(),
+            ParamValue::String(s) => s
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n"),
+            _ => self.sql(),
+        }
+    }
+}
+macro_rules! param_value {
+    ($($t: ty => $variant: ident),*) => {
+        $(
+            impl From<$t> for ParamValue {
+                fn from(x: $t) -> Self {
+                    Self::$variant(x.into())
+                }
+            }
+        )*
+    };
+}
+param_value!(
+    bool => Bool,
+    i8 => Int, i16 => Int, i32 => Int, i64 => Int,
+    u8 => UInt, u16 => UInt, u32 => UInt, u64 => UInt,
+    f32 => Float, f64 => Float,
+    &str => String, String => String
+);
+impl<T: Into<ParamValue>> From<Vec<T>> for ParamValue {
+    fn from(values: Vec<T>) -> Self {
+        Self::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+impl TryFrom<AnyValue<'_>> for ParamValue {
+    type Error = Error;
+    fn try_from(value: AnyValue<'_>) -> Result<Self, Self::Error> {
+        Ok(match value {
+            AnyValue::Null => Self::Null,
+            AnyValue::Boolean(x) => x.into(),
+            AnyValue::Int8(x) => x.into(),
+            AnyValue::Int16(x) => x.into(),
+            AnyValue::Int32(x) => x.into(),
+            AnyValue::Int64(x) => x.into(),
+            AnyValue::UInt8(x) => x.into(),
+            AnyValue::UInt16(x) => x.into(),
+            AnyValue::UInt32(x) => x.into(),
+            AnyValue::UInt64(x) => x.into(),
+            AnyValue::Float32(x) => x.into(),
+            AnyValue::Float64(x) => x.into(),
+            AnyValue::String(x) => x.into(),
+            AnyValue::StringOwned(x) => x.to_string().into(),
+            AnyValue::List(series) => Self::try_from(&series)?,
+            value => return Err(Error::UnsupportedPolarsType(value.dtype())),
+        })
+    }
+}
+/// Array parameter.
+impl TryFrom<&Series> for ParamValue {
+    type Error = Error;
+    fn try_from(series: &Series) -> Result<Self, Self::Error> {
+        let series = series.rechunk();
+        Ok(Self::Array(
+            series
+                .iter()
+                .map(Self::try_from)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+/// Query parameters, by name.
+pub type Params = IndexMap<String, ParamValue>;
+
+/// Progress of a query, cumulative since its start.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub read_rows: u64,
+    pub read_bytes: u64,
+    /// Estimate of the total number of rows to read, which can increase during the query.
+    pub total_rows_to_read: u64,
+    pub written_rows: u64,
+    pub written_bytes: u64,
+    /// Time since the start of the query, as reported by the server when available.
+    pub elapsed: Duration,
+    /// Profile of the results, sent by the native protocol before their end. The HTTP interface
+    /// does not send it.
+    pub profile: Option<ProfileInfo>,
+}
+/// Profile of the results of a query, from the ProfileInfo packet of the native protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProfileInfo {
+    pub rows: u64,
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Number of rows before the `LIMIT` of the query, if one was applied.
+    pub rows_before_limit: Option<u64>,
+}
+impl From<&klickhouse::ProfileInfo> for ProfileInfo {
+    fn from(info: &klickhouse::ProfileInfo) -> Self {
+        Self {
+            rows: info.rows,
+            blocks: info.blocks,
+            bytes: info.bytes,
+            rows_before_limit: info.applied_limit.then_some(info.rows_before_limit),
+        }
+    }
+}
+impl Progress {
+    /// Add a progress packet of the native protocol, which contains increments.
+    pub(crate) fn add(&mut self, packet: &klickhouse::Progress, elapsed: Duration) {
+        self.read_rows += packet.read_rows;
+        self.read_bytes += packet.read_bytes;
+        self.total_rows_to_read += packet.new_total_rows_to_read;
+        self.written_rows += packet.new_written_rows.unwrap_or_default();
+        self.written_bytes += packet.new_written_bytes.unwrap_or_default();
+        self.elapsed = elapsed;
+    }
+    /// Parse the `X-ClickHouse-Progress` and `X-ClickHouse-Summary` HTTP headers, e.g.
+    /// `{"read_rows":"10","read_bytes":"80","total_rows_to_read":"100","elapsed_ns":"1000"}`.
+    pub(crate) fn from_header(header: &str, elapsed: Duration) -> Self {
+        static FIELD: OnceLock<regex::Regex> = OnceLock::new();
+        let field = FIELD.get_or_init(|| regex::Regex::new(r#""(\w+)"\s*:\s*"?(\d+)"?"#).unwrap());
+        let mut progress = Self {
+            elapsed,
+            ..Default::default()
+        };
+        for captures in field.captures_iter(header) {
+            let Ok(value) = captures[2].parse::<u64>() else {
+                continue;
+            };
+            match &captures[1] {
+                "read_rows" => progress.read_rows = value,
+                "read_bytes" => progress.read_bytes = value,
+                "total_rows_to_read" => progress.total_rows_to_read = value,
+                "written_rows" => progress.written_rows = value,
+                "written_bytes" => progress.written_bytes = value,
+                "elapsed_ns" => progress.elapsed = Duration::from_nanos(value),
+                _ => {}
+            }
+        }
+        progress
+    }
+}
+
+/// Receiver of the [Progress] updates of a query.
+///
+/// With the native protocol, updates are sent as the server reports them, recognized by the id of
+/// the query, and the last ones are reported before the end of the results.
+///
+/// With the HTTP interface, the updates are read from the `X-ClickHouse-Progress` and
+/// `X-ClickHouse-Summary` response headers. As headers cannot follow the body, the server only
+/// sends them until the results start streaming (or, for inserts, once the query is complete),
+/// so they are all reported at once, when the response starts, rather than during the query.
+#[derive(Clone)]
+pub struct ProgressHandler(Arc<dyn Fn(Progress) + Send + Sync>);
+impl ProgressHandler {
+    pub fn new(callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+    /// Handler sending the updates to a channel.
+    pub fn channel() -> (Self, tokio::sync::mpsc::UnboundedReceiver<Progress>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        (
+            Self::new(move |progress| {
+                let _ = tx.send(progress);
+            }),
+            rx,
+        )
+    }
+    pub(crate) fn report(&self, progress: Progress) {
+        (self.0)(progress)
+    }
+}
+impl std::fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ProgressHandler")
+    }
+}
+
+/// Options of a single query.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    /// Settings for this query, taking precedence over the defaults of the [Client](crate::Client).
+    pub settings: Settings,
+    /// Values of the `{name:Type}` placeholders of the query, bound by the server.
+    pub params: Params,
+    pub progress: Option<ProgressHandler>,
+    /// Id of the query on the server, generated if not given. Retries of the query use it with an
+    /// `-retry<n>` suffix, as the server rejects an id while a query with it is still running.
+    pub query_id: Option<String>,
+}
+impl QueryOptions {
+    /// Options of the given attempt of the query, starting from 1.
+    pub(crate) fn for_attempt(&self, attempt: usize) -> Self {
+        let mut options = self.clone();
+        if attempt > 1 {
+            if let Some(query_id) = &mut options.query_id {
+                *query_id = format!("{query_id}-retry{}", attempt - 1);
+            }
+        }
+        options
+    }
+    /// Add default settings, which are overridden by the ones of the query.
+    pub(crate) fn with_default_settings(mut self, defaults: &Settings) -> Self {
+        let mut settings = defaults.clone();
+        settings.extend(self.settings);
+        self.settings = settings;
+        self
+    }
+}
+
+/// Token of a query, see [tokenize].
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// Keyword, identifier or number.
+    Word(&'a str),
+    /// String literal or quoted identifier.
+    Quoted,
+    /// Parenthesized or bracketed expression.
+    Group,
+    Symbol(char),
+}
+
+/// Top-level tokens of a query with their byte ranges. Comments are skipped, and the contents
+/// of literals and parentheses are not tokenized.
+fn tokenize(query: &str) -> Vec<(Range<usize>, Token<'_>)> {
+    let bytes = query.as_bytes();
+    let line_end = |i: usize| query[i..].find('\n').map_or(bytes.len(), |j| i + j);
+    let mut tokens = vec![];
+    let (mut depth, mut group_start) = (0usize, 0);
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let token = match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = line_end(i);
+                continue;
+            }
+            b'#' => {
+                i = line_end(i);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = query[i + 2..].find("*/").map_or(bytes.len(), |j| i + j + 4);
+                continue;
+            }
+            quote @ (b'\'' | b'"' | b'`') => {
+                i += 1;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'\\' => i += 2,
+                        c if c == quote && bytes.get(i + 1) == Some(&quote) => i += 2,
+                        c if c == quote => break,
+                        _ => i += 1,
+                    }
+                }
+                i = (i + 1).min(bytes.len());
+                Token::Quoted
+            }
+            b'(' | b'[' => {
+                if depth == 0 {
+                    group_start = start;
+                }
+                depth += 1;
+                i += 1;
+                continue;
+            }
+            b')' | b']' if depth > 0 => {
+                depth -= 1;
+                i += 1;
+                if depth == 0 {
+                    tokens.push((group_start..i, Token::Group));
+                }
+                continue;
+            }
+            c if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            c if c.is_ascii_alphanumeric() || c == b'_' || !c.is_ascii() => {
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric()
+                        || matches!(bytes[i], b'_' | b'.')
+                        || !bytes[i].is_ascii())
+                {
+                    i += 1;
+                }
+                Token::Word(&query[start..i])
+            }
+            c => {
+                i += 1;
+                Token::Symbol(c as char)
+            }
+        };
+        if depth == 0 {
+            tokens.push((start..i, token));
+        }
+    }
+    if depth > 0 {
+        tokens.push((group_start..bytes.len(), Token::Group));
+    }
+    tokens
+}
+
+/// Whether the tokens are `name = value` pairs separated by commas, i.e. a `SETTINGS` clause.
+fn is_settings_list(tokens: &[(Range<usize>, Token)]) -> bool {
+    !tokens.is_empty()
+        && tokens
+            .split(|(_, token)| token == &Token::Symbol(','))
+            .all(|pair| {
+                let pair: Vec<&Token> = pair.iter().map(|(_, token)| token).collect();
+                matches!(
+                    pair.as_slice(),
+                    [
+                        Token::Word(_),
+                        Token::Symbol('='),
+                        Token::Word(_) | Token::Quoted
+                    ] | [
+                        Token::Word(_),
+                        Token::Symbol('='),
+                        Token::Symbol('-'),
+                        Token::Word(_)
+                    ]
+                )
+            })
+}
+
+/// Add a `SETTINGS` clause to a query, for clients that cannot pass settings separately.
+///
+/// In inserts, the clause is placed before the data (`FORMAT`, `VALUES` or `SELECT`). In other
+/// queries, it is placed before a trailing `FORMAT`, and extends a trailing `SETTINGS` clause if
+/// there is one. Trailing comments and semicolons are removed.
+pub(crate) fn add_settings_clause(query: &str, settings: &Settings) -> Result<String, Error> {
+    static NAME: OnceLock<regex::Regex> = OnceLock::new();
+    if settings.is_empty() {
+        return Ok(query.to_string());
+    }
+    let name = NAME.get_or_init(|| regex::Regex::new(r"^[A-Za-z_]\w*$").unwrap());
+    if let Some(invalid) = settings.keys().find(|n| !name.is_match(n)) {
+        return Err(Error::InvalidSetting(invalid.clone()));
+    }
+    let clause = settings
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name, value.sql()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut tokens = tokenize(query);
+    while matches!(tokens.last(), Some((_, Token::Symbol(';')))) {
+        tokens.pop();
+    }
+    let end = tokens.last().map_or(0, |(range, _)| range.end);
+    let is_word =
+        |token: &Token, word: &str| matches!(token, Token::Word(w) if w.eq_ignore_ascii_case(word));
+    let (position, extend) = if tokens.first().is_some_and(|(_, t)| is_word(t, "INSERT")) {
+        let data = tokens
+            .iter()
+            .position(|(_, t)| {
+                ["FORMAT", "VALUES", "SELECT", "WITH"]
+                    .iter()
+                    .any(|w| is_word(t, w))
+            })
+            .unwrap_or(tokens.len());
+        let extend = tokens[..data].iter().any(|(_, t)| is_word(t, "SETTINGS"));
+        (data, extend)
+    } else {
+        let n = tokens.len();
+        let format = if n >= 2 && is_word(&tokens[n - 2].1, "FORMAT") {
+            n - 2
+        } else {
+            n
+        };
+        let extend = tokens[..format]
+            .iter()
+            .rposition(|(_, t)| is_word(t, "SETTINGS"))
+            .is_some_and(|i| is_settings_list(&tokens[i + 1..format]));
+        (format, extend)
+    };
+    let offset = tokens.get(position).map_or(end, |(range, _)| range.start);
+    let query = format!(
+        "{}{} {} {}",
+        query[..offset].trim_end(),
+        if extend { "," } else { " SETTINGS" },
+        clause,
+        &query[offset..end]
+    );
+    Ok(query.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn settings_clause() -> anyhow::Result<()> {
+        let settings: Settings = [
+            ("max_threads".to_string(), 8.into()),
+            ("log_comment".to_string(), "it's".into()),
+        ]
+        .into_iter()
+        .collect();
+        let clause = "max_threads = 8, log_comment = 'it\\'s'";
+        for (query, expected) in [
+            ("SELECT 1;", format!("SELECT 1 SETTINGS {}", clause)),
+            (
+                "SELECT 1 -- the FORMAT is set by the client\n",
+                format!("SELECT 1 SETTINGS {}", clause),
+            ),
+            (
+                "SELECT 'a -- b' FORMAT TSV /* comment */;",
+                format!("SELECT 'a -- b' SETTINGS {} FORMAT TSV", clause),
+            ),
+            (
+                "SELECT * FROM t WHERE name = 'SETTINGS x = 1'",
+                format!(
+                    "SELECT * FROM t WHERE name = 'SETTINGS x = 1' SETTINGS {}",
+                    clause
+                ),
+            ),
+            (
+                "SELECT 1 SETTINGS join_use_nulls = 1, max_block_size = -1",
+                format!(
+                    "SELECT 1 SETTINGS join_use_nulls = 1, max_block_size = -1, {}",
+                    clause
+                ),
+            ),
+            (
+                "SELECT * FROM (SELECT 1 SETTINGS max_threads = 1)",
+                format!(
+                    "SELECT * FROM (SELECT 1 SETTINGS max_threads = 1) SETTINGS {}",
+                    clause
+                ),
+            ),
+            (
+                "INSERT INTO `t` FORMAT native",
+                format!("INSERT INTO `t` SETTINGS {} FORMAT native", clause),
+            ),
+            (
+                "INSERT INTO t (a, b) VALUES (1, 'x -- y')",
+                format!(
+                    "INSERT INTO t (a, b) SETTINGS {} VALUES (1, 'x -- y')",
+                    clause
+                ),
+            ),
+            (
+                "INSERT INTO t SETTINGS async_insert = 1 SELECT 1 FORMAT TSV",
+                format!(
+                    "INSERT INTO t SETTINGS async_insert = 1, {} SELECT 1 FORMAT TSV",
+                    clause
+                ),
+            ),
+        ] {
+            assert_eq!(add_settings_clause(query, &settings)?, expected);
+        }
+        assert_eq!(
+            add_settings_clause("SELECT * FROM t", &Default::default())?,
+            "SELECT * FROM t"
+        );
+        let invalid: Settings = [("max_threads = 1; DROP".to_string(), 1.into())]
+            .into_iter()
+            .collect();
+        assert!(matches!(
+            add_settings_clause("SELECT 1", &invalid),
+            Err(Error::InvalidSetting(_))
+        ));
+        Ok(())
+    }
+    #[test]
+    fn params() -> anyhow::Result<()> {
+        let params: Params = [
+            ("name".to_string(), "O'Neil".into()),
+            (
+                "ages".to_string(),
+                ParamValue::try_from(&Series::new("", [Some(30i32), None]))?,
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(params["name"].sql(), "'O\\'Neil'");
+        assert_eq!(params["name"].text(), "O'Neil");
+        assert_eq!(params["ages"].text(), "[30, NULL]");
+        Ok(())
+    }
+    #[test]
+    fn progress_header() {
+        assert_eq!(
+            Progress::from_header(
+                r#"{"read_rows":"10","read_bytes":"80","written_rows":"0","written_bytes":"0","total_rows_to_read":"100","result_rows":"0","elapsed_ns":"1500"}"#,
+                Duration::from_secs(1)
+            ),
+            Progress {
+                read_rows: 10,
+                read_bytes: 80,
+                total_rows_to_read: 100,
+                elapsed: Duration::from_nanos(1500),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            Progress::from_header(r#"{"written_rows":"5"}"#, Duration::from_secs(1)),
+            Progress {
+                written_rows: 5,
+                elapsed: Duration::from_secs(1),
+                ..Default::default()
+            }
+        );
+    }
+}
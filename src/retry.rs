@@ -0,0 +1,190 @@
+//! Retries of queries failing with transient errors, see [RetryPolicy].
+
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tracing::*;
+
+use crate::{Error, HttpError};
+
+/// Retries of read queries failing with transient errors, with exponential backoff.
+///
+/// Queries are only retried when they fail before returning results. Inserts are only retried
+/// by [ClickhouseTable::insert_df](crate::ClickhouseTable::insert_df), when the
+/// `insert_deduplication_token` setting is passed. The server then discards the blocks it already
+/// received, provided that the table deduplicates inserts: `Replicated*MergeTree` tables do by
+/// default, and other `MergeTree` tables only with the `non_replicated_deduplication_window`
+/// setting.
+///
+/// The native client does not reconnect, hence its I/O errors are not retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximal number of attempts, including the first one.
+    pub max_attempts: usize,
+    /// Delay before the first retry, multiplied by `backoff_multiplier` at each further attempt.
+    pub initial_backoff: Duration,
+    pub backoff_multiplier: f64,
+    pub max_backoff: Duration,
+    /// Retry connection failures and I/O errors of the HTTP client, as well as HTTP errors
+    /// without a server exception (e.g. from proxies) with a 5xx or 429 status.
+    pub network: bool,
+    /// Names of the server exception codes to retry.
+    pub server_codes: Vec<String>,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            backoff_multiplier: 2.0,
+            max_backoff: Duration::from_secs(10),
+            network: true,
+            server_codes: [
+                "TOO_MANY_SIMULTANEOUS_QUERIES",
+                "TOO_MANY_PARTS",
+                "NETWORK_ERROR",
+                "SOCKET_TIMEOUT",
+                "ALL_CONNECTION_TRIES_FAILED",
+                "KEEPER_EXCEPTION",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        match error {
+            Error::Server(e) => self.server_codes.contains(&e.name),
+            Error::Insertion { source, .. } => self.is_retryable(source),
+            Error::Http(HttpError::Request(e)) => {
+                self.network && (e.is_connect() || e.is_timeout())
+            }
+            Error::Http(HttpError::Server { status, .. }) => {
+                self.network && (*status >= 500 || *status == 429)
+            }
+            Error::Http(HttpError::IO(_)) | Error::Io(_) => self.network,
+            _ => false,
+        }
+    }
+    /// Delay before the given retry, starting from 1.
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = self.backoff_multiplier.powi(retry as i32 - 1);
+        self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+    }
+    /// Run the attempts until success, a non-retryable error, or the maximal number of attempts.
+    /// The attempts are given their number, starting from 1.
+    pub(crate) async fn run<T, F: Future<Output = Result<T, Error>>>(
+        &self,
+        mut attempt: impl FnMut(usize) -> F,
+    ) -> Result<T, Error> {
+        let mut retry = 1;
+        loop {
+            match attempt(retry).await {
+                Err(e) if retry < self.max_attempts && self.is_retryable(&e) => {
+                    let backoff = self.backoff(retry);
+                    warn!(retry, ?backoff, "Retrying after error: {}", e);
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether the query only reads data, and can therefore be retried.
+pub(crate) fn is_read_query(query: &str) -> bool {
+    static READ: OnceLock<regex::Regex> = OnceLock::new();
+    READ.get_or_init(|| {
+        // Skipping whitespace, parentheses and comments.
+        regex::Regex::new(
+            r"(?i)^(?:[\s(]|--[^\n]*\n|#[^\n]*\n|/\*(?s:.*?)\*/)*(SELECT|WITH|DESCRIBE|DESC|SHOW|EXISTS|EXPLAIN)\b",
+        )
+        .unwrap()
+    })
+    .is_match(query)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::ServerException;
+    #[test]
+    fn retries() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(20), Duration::from_secs(10));
+
+        let error = |name: &str| {
+            Error::Server(ServerException {
+                code: 0,
+                name: name.into(),
+                message: String::new(),
+                stack_trace: String::new(),
+            })
+        };
+        assert!(policy.is_retryable(&error("TOO_MANY_SIMULTANEOUS_QUERIES")));
+        assert!(!policy.is_retryable(&error("UNKNOWN_TABLE")));
+        assert!(policy.is_retryable(&Error::Insertion {
+            rows: 0,
+            written_rows: None,
+            source: Box::new(error("TOO_MANY_PARTS"))
+        }));
+
+        assert!(is_read_query(" (SELECT 1)"));
+        assert!(is_read_query("with x AS (SELECT 1) SELECT * FROM x"));
+        assert!(!is_read_query("INSERT INTO t SELECT 1"));
+        assert!(!is_read_query("SELECTED"));
+        assert!(is_read_query("-- export\n/* a\nb */ SELECT 1"));
+        assert!(!is_read_query("-- SELECT\nINSERT INTO t SELECT 1"));
+
+        let http = |status| {
+            Error::Http(HttpError::Server {
+                status,
+                message: String::new(),
+            })
+        };
+        assert!(policy.is_retryable(&http(503)));
+        assert!(policy.is_retryable(&http(429)));
+        assert!(!policy.is_retryable(&http(400)));
+    }
+    #[tokio::test]
+    async fn run() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let error = |name: &str| {
+            Error::Server(ServerException {
+                code: 0,
+                name: name.into(),
+                message: String::new(),
+                stack_trace: String::new(),
+            })
+        };
+        let attempts = &AtomicUsize::new(0);
+        let run = |name: &'static str, successful_attempt: usize| {
+            attempts.store(0, Ordering::SeqCst);
+            policy.run(move |number| async move {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                assert_eq!(number, attempt);
+                if attempt == successful_attempt {
+                    Ok(attempt)
+                } else {
+                    Err(error(name))
+                }
+            })
+        };
+        assert!(run("TOO_MANY_PARTS", 0).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), policy.max_attempts);
+        assert_eq!(run("TOO_MANY_PARTS", 2).await.unwrap(), 2);
+        assert!(run("UNKNOWN_TABLE", 2).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
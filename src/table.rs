@@ -1,13 +1,24 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Poll},
+};
 
-use futures::stream::{self, TryStreamExt};
+use futures::stream::{self, Stream, TryStreamExt};
 use itertools::Itertools;
 use klickhouse::IndexMap;
 use polars::prelude::*;
 use tracing::*;
 
-use super::{structs, ClickhouseType, Error};
-use crate::{clickhouse::ClientGeneric, p2c::BlockIntoIterator};
+use super::{structs, ClickhouseType, Error, IncompatibleColumn};
+use crate::{
+    clickhouse::{ClientGeneric, ColumnarFormat},
+    p2c::{self, Blocks, Coercion, DecimalRounding, NonFinitePolicy},
+    query::{Params, ProgressHandler, QueryOptions, Settings},
+};
 
 pub type ValueMap = IndexMap<String, klickhouse::Value>;
 
@@ -27,6 +38,55 @@ pub struct TableCreationOptions<'a> {
     pub if_not_exists: bool,
 }
 
+/// Retrieve column types with `DESCRIBE <subject>`, where the subject is a table or a query.
+pub(crate) async fn describe(
+    subject: &str,
+    params: &Params,
+    client: &impl ClientGeneric,
+) -> Result<IndexMap<String, ClickhouseType>, Error> {
+    #[derive(klickhouse::Row, Debug)]
+    struct SchemaRow {
+        name: String,
+        #[klickhouse(rename = "type")]
+        type_: String,
+    }
+    client
+        .query_with::<SchemaRow>(
+            format!("DESCRIBE {}", subject),
+            QueryOptions {
+                params: params.clone(),
+                ..Default::default()
+            },
+        )
+        .await?
+        .map_err(Error::from)
+        .and_then(|row| async move {
+            row.type_
+                .parse::<ClickhouseType>()
+                .map(|type_| (row.name, type_))
+        })
+        .try_collect()
+        .await
+}
+
+#[derive(Default)]
+pub struct InsertOptions {
+    /// Constant values for columns present in the table but not in the dataframe.
+    pub defaults: ValueMap,
+    pub coercion: Coercion,
+    pub non_finite: NonFinitePolicy,
+    pub decimal_rounding: DecimalRounding,
+    /// Maximal number of rows per block, 200 000 by default.
+    pub max_block_rows: Option<usize>,
+    /// Approximate size of the blocks in bytes, estimated from the memory footprint of the
+    /// dataframe. The blocks still have at most [max_block_rows](Self::max_block_rows) rows.
+    pub target_block_bytes: Option<usize>,
+    /// Clickhouse settings for the `INSERT` query.
+    pub settings: Settings,
+    /// Receive the progress of the `INSERT` query, see [ProgressHandler].
+    pub progress: Option<ProgressHandler>,
+}
+
 impl ClickhouseTable {
     pub fn types_all(&self) -> String {
         self.types
@@ -40,25 +100,9 @@ impl ClickhouseTable {
     /// Indeed, Clickhouse returns for example booleans as the internal storage type ([u8]).
     pub async fn from_server(table: &str, client: &impl ClientGeneric) -> Result<Self, Error> {
         debug!(table, "Retrieving table information");
-        #[derive(klickhouse::Row, Debug)]
-        struct SchemaRow {
-            name: String,
-            #[klickhouse(rename = "type")]
-            type_: String,
-        }
         Ok(Self {
             name: table.into(),
-            types: client
-                .query::<SchemaRow>(format!("DESCRIBE TABLE {}", table))
-                .await?
-                .map_err(Error::from)
-                .and_then(|row| async move {
-                    row.type_
-                        .parse::<ClickhouseType>()
-                        .map(|type_| (row.name, type_))
-                })
-                .try_collect()
-                .await?,
+            types: describe(&format!("TABLE {}", table), &Default::default(), client).await?,
         })
     }
     pub async fn get_df_query(
@@ -152,47 +196,185 @@ impl ClickhouseTable {
             .execute([self.create_query(options)?, suffix].join("\n"))
             .await
     }
+    /// Check that the columns of a dataframe with the given schema can be inserted into the table.
+    ///
+    /// All unsupported or incompatible columns are reported at once in
+    /// [Error::IncompatibleColumns], with a suggested cast when possible. Columns missing from the
+    /// table are ignored here.
+    pub fn validate_schema(&self, schema: &Schema) -> Result<(), Error> {
+        let schema = structs::flatten_schema(schema)?;
+        let incompatible: Vec<IncompatibleColumn> = schema
+            .iter()
+            .filter_map(|(col, dtype)| {
+                let type_ = self.types.get(col.as_str())?;
+                let suggested_cast = p2c::check_insertable(dtype, type_).err()?;
+                Some(IncompatibleColumn {
+                    column: col.to_string(),
+                    dtype: dtype.clone(),
+                    type_: type_.clone(),
+                    suggested_cast,
+                })
+            })
+            .collect();
+        if incompatible.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::IncompatibleColumns(incompatible))
+        }
+    }
     /// Insert a [DataFrame] in Clickhouse.
-    /// The schemas must match.
-    /// The [defaults] argument specifies constant values for columns present in the table but not
-    /// in the dataframe.
+    /// The schemas must match, up to the defaults in [InsertOptions].
+    ///
+    /// The blocks are sent within a single `INSERT` query, and converted on a blocking thread while
+    /// they are sent, a few blocks ahead. The first block is converted before opening the query, so
+    /// that a conversion failure there inserts nothing, while a later failure abandons the query so
+    /// that the server rejects it. If the query fails or is abandoned, [Error::Insertion] reports
+    /// the number of rows sent and the number of rows written as last reported by the server.
+    /// Clickhouse may have committed part of the rows sent.
+    ///
+    /// The insertion is retried with the [retry policy](ClientGeneric::retry_policy) of the client
+    /// only when `insert_deduplication_token` is set in [InsertOptions::settings], in which case
+    /// the server discards the blocks it already received. This requires a table deduplicating
+    /// inserts, see [RetryPolicy](crate::RetryPolicy).
     pub async fn insert_df(
         &self,
         df: DataFrame,
-        defaults: ValueMap,
+        options: InsertOptions,
         client: &impl ClientGeneric,
     ) -> Result<(), Error> {
         debug!(self.name, shape = ?df.shape(), "Inserting dataframe",);
         let df = structs::flatten(df)?;
-        if df.should_rechunk() {
-            return Err(Error::ShouldRechunk);
+        let df: DataFrame = df
+            .get_columns()
+            .iter()
+            .map(|col| match self.types.get(col.name()) {
+                Some(type_) => {
+                    let col = p2c::coerce(col.clone(), type_, options.coercion)?;
+                    p2c::handle_non_finite(col, options.non_finite, type_)
+                }
+                // Reported when building the blocks.
+                None => Ok(col.clone()),
+            })
+            .try_collect()?;
+        self.validate_schema(&df.schema())?;
+        let rows = p2c::block_rows(&df, options.max_block_rows, options.target_block_bytes);
+        let blocks = self.blocks_from_df(df, options.defaults, options.decimal_rounding, rows)?;
+        if blocks.df.height() == 0 {
+            debug!(self.name, "Nothing to insert");
+            return Ok(());
         }
-        let blocks = self.blocks_from_df(df, &defaults)?;
-
-        let query = format!("INSERT INTO `{}` FORMAT native", self.name);
-        for mut block in blocks.try_into_iter()? {
-            debug!(rows = block.rows, "Inserting block");
-            for (k, v) in defaults.clone() {
-                block
-                    .column_data
-                    .entry(k)
-                    .or_insert_with(|| std::iter::repeat(v).take(block.rows as usize).collect());
+
+        let columnar = client.supports_columnar().then(|| client.columnar_format());
+        let format = columnar.unwrap_or(ColumnarFormat::Native);
+        let query = format!("INSERT INTO `{}` FORMAT {}", self.name, format.name());
+        let query_options = QueryOptions {
+            settings: options.settings,
+            progress: options.progress,
+            ..Default::default()
+        };
+        // The blocks are converted again by each attempt.
+        let attempt = || {
+            let blocks = blocks.clone();
+            self.insert_blocks(
+                query.clone(),
+                blocks,
+                columnar,
+                query_options.clone(),
+                client,
+            )
+        };
+        match client.retry_policy() {
+            Some(retry)
+                if query_options
+                    .settings
+                    .contains_key("insert_deduplication_token") =>
+            {
+                retry.run(|_| attempt()).await
             }
-            client
-                .insert_native_raw(query.clone(), stream::iter([block]))
-                .await?
-                .try_collect::<Vec<_>>()
-                .await?;
+            _ => attempt().await,
+        }
+    }
+    /// Send the blocks within a single `INSERT` query, in the columnar format if given, and as
+    /// values otherwise.
+    async fn insert_blocks(
+        &self,
+        query: String,
+        blocks: Blocks,
+        columnar: Option<ColumnarFormat>,
+        mut query_options: QueryOptions,
+        client: &impl ClientGeneric,
+    ) -> Result<(), Error> {
+        let blocks = match columnar {
+            Some(ColumnarFormat::Native) => {
+                EncodedBlocks::Columnar(ConvertedBlocks::start(blocks.into_native_blocks()).await?)
+            }
+            Some(ColumnarFormat::ArrowStream) => {
+                EncodedBlocks::Columnar(ConvertedBlocks::start(blocks.into_arrow_blocks()).await?)
+            }
+            None => {
+                EncodedBlocks::Values(ConvertedBlocks::start(blocks.into_value_blocks()).await?)
+            }
+        };
+        let sent = Arc::new(AtomicUsize::new(0));
+        let written_rows = Arc::new(Mutex::new(None));
+        let handler = query_options.progress.take();
+        query_options.progress = Some(ProgressHandler::new({
+            let written_rows = written_rows.clone();
+            move |progress| {
+                *written_rows.lock().unwrap() = Some(progress.written_rows);
+                if let Some(handler) = &handler {
+                    handler.report(progress);
+                }
+            }
+        }));
+        let (failure, failed) = tokio::sync::oneshot::channel();
+        let insert = async {
+            match blocks {
+                EncodedBlocks::Columnar(blocks) => {
+                    let blocks = blocks.stream(&sent, failure);
+                    client.insert_columnar(query, blocks, query_options).await
+                }
+                EncodedBlocks::Values(blocks) => {
+                    let blocks = blocks.stream(&sent, failure);
+                    client
+                        .insert_native_raw(query, blocks, query_options)
+                        .await?
+                        .try_collect::<Vec<_>>()
+                        .await?;
+                    Ok(())
+                }
+            }
+        };
+        // On a conversion failure, the insert is dropped before the end of the blocks.
+        let result = tokio::select! {
+            result = insert => result,
+            Ok(e) = failed => Err(e),
+        };
+        let rows = sent.load(Ordering::SeqCst);
+        let written_rows = written_rows.lock().unwrap().take();
+        match result {
+            Ok(()) => {
+                debug!(
+                    self.name,
+                    rows, written_rows, "Finished inserting dataframe"
+                );
+                Ok(())
+            }
+            Err(e) => Err(Error::Insertion {
+                rows,
+                written_rows,
+                source: Box::new(e),
+            }),
         }
-        debug!(self.name, "Finished inserting dataframe");
-        Ok(())
     }
     /// Create blocks to send to Clickhouse from a DataFrame.
     fn blocks_from_df(
         &self,
         df: DataFrame,
-        defaults: &ValueMap,
-    ) -> Result<BlockIntoIterator, Error> {
+        defaults: ValueMap,
+        decimal_rounding: DecimalRounding,
+        rows: usize,
+    ) -> Result<Blocks, Error> {
         let mut df_cols: HashSet<_> = df.get_column_names().into_iter().collect();
         let table_cols: HashSet<_> = self.types.keys().map(String::as_str).collect();
 
@@ -209,9 +391,237 @@ impl ClickhouseTable {
                 table_cols.difference(&df_cols)
             )));
         }
-        Ok(BlockIntoIterator {
+        Ok(Blocks {
             df,
             cols: self.types.clone(),
+            defaults,
+            decimal_rounding,
+            rows,
         })
     }
 }
+
+/// Blocks converted for the client.
+enum EncodedBlocks {
+    Columnar(ConvertedBlocks<Vec<u8>>),
+    Values(ConvertedBlocks<klickhouse::block::Block>),
+}
+
+/// Number of blocks converted ahead of the ones being sent.
+const LOOKAHEAD_BLOCKS: usize = 2;
+
+/// Blocks converted on a blocking thread, with their number of rows, at most
+/// [LOOKAHEAD_BLOCKS] ahead of the ones being sent.
+struct ConvertedBlocks<T> {
+    first: Option<(usize, T)>,
+    rest: tokio::sync::mpsc::Receiver<Result<(usize, T), Error>>,
+}
+impl<T: Send + Sync + 'static> ConvertedBlocks<T> {
+    /// Start the conversion, and wait for the first block so that its failure is reported before
+    /// the query is sent.
+    async fn start(
+        blocks: impl Iterator<Item = Result<(usize, T), Error>> + Send + 'static,
+    ) -> Result<Self, Error> {
+        let (tx, mut rest) = tokio::sync::mpsc::channel(LOOKAHEAD_BLOCKS);
+        tokio::task::spawn_blocking(move || {
+            for block in blocks {
+                let failed = block.is_err();
+                if tx.blocking_send(block).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let first = rest.recv().await.transpose()?;
+        Ok(Self { first, rest })
+    }
+    /// Stream the blocks to the client, counting the rows sent.
+    ///
+    /// A conversion failure is sent to `failure`, after which the stream stays pending rather than
+    /// ending, so that the query is abandoned rather than completed with the blocks sent so far.
+    fn stream(
+        mut self,
+        sent: &Arc<AtomicUsize>,
+        failure: tokio::sync::oneshot::Sender<Error>,
+    ) -> impl Stream<Item = T> + Send + Sync + Unpin + 'static {
+        let sent = sent.clone();
+        let mut failure = Some(failure);
+        stream::poll_fn(move |cx| {
+            if failure.is_none() {
+                return Poll::Pending;
+            }
+            let block = match self.first.take() {
+                Some(block) => Ok(block),
+                None => match ready!(self.rest.poll_recv(cx)) {
+                    Some(block) => block,
+                    None => return Poll::Ready(None),
+                },
+            };
+            match block {
+                Ok((rows, block)) => {
+                    debug!(rows, "Sending block");
+                    sent.fetch_add(rows, Ordering::SeqCst);
+                    Poll::Ready(Some(block))
+                }
+                Err(e) => {
+                    if let Some(failure) = failure.take() {
+                        failure.send(e).ok();
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use klickhouse::block::Block;
+
+    use super::*;
+    use crate::{RetryPolicy, ServerException};
+
+    /// Client whose inserts fail with a retryable server exception.
+    struct FailingClient {
+        attempts: AtomicUsize,
+        retry: RetryPolicy,
+    }
+    impl ClientGeneric for FailingClient {
+        fn sends_initial_block(&self) -> bool {
+            false
+        }
+        async fn insert_native_raw(
+            &self,
+            _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            _blocks: impl Stream<Item = Block> + Send + Sync + Unpin + 'static,
+            _options: QueryOptions,
+        ) -> Result<impl Stream<Item = Result<Block, Error>> + 'static, Error> {
+            Ok(stream::empty::<Result<Block, Error>>())
+        }
+        async fn query_raw(
+            &self,
+            _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            _options: QueryOptions,
+        ) -> Result<impl Stream<Item = Result<Block, Error>> + Unpin + 'static, Error> {
+            Ok(stream::empty::<Result<Block, Error>>())
+        }
+        fn supports_columnar(&self) -> bool {
+            true
+        }
+        async fn insert_columnar(
+            &self,
+            _query: impl TryInto<klickhouse::ParsedQuery, Error = klickhouse::KlickhouseError> + 'static,
+            blocks: impl Stream<Item = Vec<u8>> + Send + Sync + Unpin + 'static,
+            _options: QueryOptions,
+        ) -> Result<(), Error> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            blocks.collect::<Vec<_>>().await;
+            Err(Error::Server(ServerException {
+                code: 252,
+                name: "TOO_MANY_PARTS".into(),
+                message: String::new(),
+                stack_trace: String::new(),
+            }))
+        }
+        fn retry_policy(&self) -> Option<&RetryPolicy> {
+            Some(&self.retry)
+        }
+    }
+    #[tokio::test]
+    async fn insert_retries() -> anyhow::Result<()> {
+        let table = ClickhouseTable {
+            name: "test".into(),
+            types: [("a".to_string(), "Int64".parse()?)].into_iter().collect(),
+        };
+        let df = df!("a" => [1i64, 2])?;
+        for (token, attempts) in [(false, 1), (true, 3)] {
+            let client = FailingClient {
+                attempts: AtomicUsize::new(0),
+                retry: RetryPolicy {
+                    initial_backoff: Duration::ZERO,
+                    ..Default::default()
+                },
+            };
+            let mut options = InsertOptions::default();
+            if token {
+                options
+                    .settings
+                    .insert("insert_deduplication_token".into(), "token".into());
+            }
+            assert!(table.insert_df(df.clone(), options, &client).await.is_err());
+            assert_eq!(client.attempts.load(Ordering::SeqCst), attempts);
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn insert_conversion_failure() -> anyhow::Result<()> {
+        let table = ClickhouseTable {
+            name: "test".into(),
+            types: [("a".to_string(), "Decimal(3, 1)".parse()?)]
+                .into_iter()
+                .collect(),
+        };
+        let options = || InsertOptions {
+            max_block_rows: Some(1),
+            ..Default::default()
+        };
+        // The first block overflows, so the query is not sent at all.
+        let client = FailingClient {
+            attempts: AtomicUsize::new(0),
+            retry: Default::default(),
+        };
+        let df = df!("a" => [1e9, 1.5f64])?;
+        let result = table.insert_df(df, options(), &client).await;
+        assert!(matches!(result, Err(Error::DecimalOverflow(..))));
+        assert_eq!(client.attempts.load(Ordering::SeqCst), 0);
+        // The third block overflows, so the query is abandoned after the first two.
+        let df = df!("a" => [1.5f64, 2.5, 1e9, 3.5])?;
+        let result = table.insert_df(df, options(), &client).await;
+        let Err(Error::Insertion { rows, source, .. }) = result else {
+            panic!("Expected an insertion error, got {:?}", result);
+        };
+        assert_eq!(rows, 2);
+        assert!(matches!(*source, Error::DecimalOverflow(..)));
+        assert_eq!(client.attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+    #[test]
+    fn validate_schema() -> anyhow::Result<()> {
+        let table = ClickhouseTable {
+            name: "test".into(),
+            types: [
+                ("a", "Int64"),
+                ("b", "Nullable(String)"),
+                ("c", "Array(Float64)"),
+                ("d", "Decimal(10, 2)"),
+                ("e", "Int32"),
+            ]
+            .into_iter()
+            .map(|(col, type_)| Ok((col.to_string(), type_.parse::<ClickhouseType>()?)))
+            .collect::<Result<_, Error>>()?,
+        };
+        let schema = Schema::from_iter([
+            Field::new("a", DataType::Int32),
+            Field::new("b", DataType::Null),
+            Field::new("c", DataType::List(Box::new(DataType::Int64))),
+            Field::new("d", DataType::Float32),
+            Field::new("e", DataType::Int32),
+        ]);
+        let Err(Error::IncompatibleColumns(cols)) = table.validate_schema(&schema) else {
+            panic!("Expected incompatible columns");
+        };
+        assert_eq!(
+            cols.iter()
+                .map(|c| (c.column.as_str(), c.suggested_cast.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a", Some(DataType::Int64)),
+                ("b", Some(DataType::String)),
+                ("c", Some(DataType::List(Box::new(DataType::Float64)))),
+            ]
+        );
+        Ok(())
+    }
+}
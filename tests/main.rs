@@ -1,7 +1,10 @@
+use futures::TryStreamExt;
 use polars::prelude::*;
 use yare::parameterized;
 
-use polarhouse::{ClientGeneric, GetOptions, HttpClient, TableCreationOptions};
+use polarhouse::{
+    ClientGeneric, ColumnarFormat, GetOptions, HttpClientBuilder, TableCreationOptions,
+};
 
 fn create_df() -> anyhow::Result<DataFrame> {
     let name = Series::new("name", &["Batman", "Superman"]);
@@ -32,7 +35,12 @@ fn create_df() -> anyhow::Result<DataFrame> {
     Ok([name, is_rich, age, powers, address].into_iter().collect())
 }
 
-async fn retrieve(df: DataFrame, table_name: &str, ch: impl ClientGeneric) -> anyhow::Result<()> {
+async fn retrieve(
+    df: DataFrame,
+    table_name: &str,
+    ch: impl ClientGeneric,
+    http: bool,
+) -> anyhow::Result<()> {
     // Retrieve dataframe from Clickhouse
     let df2 = polarhouse::get_df_query(
         klickhouse::SelectBuilder::new(table_name).select("*"),
@@ -56,6 +64,21 @@ async fn retrieve(df: DataFrame, table_name: &str, ch: impl ClientGeneric) -> an
     println!("{}", df2);
     assert_eq!(df2.get_column_names().len(), 7);
 
+    // Stream the results in batches
+    let dfs: Vec<DataFrame> = polarhouse::get_df_stream(
+        klickhouse::SelectBuilder::new(table_name).select("*"),
+        GetOptions {
+            batch_rows: Some(1),
+            ..Default::default()
+        },
+        &ch,
+    )
+    .await?
+    .try_collect()
+    .await?;
+    assert_eq!(dfs.len(), 2);
+    assert!(dfs.iter().all(|df2| df2.schema() == df.schema()));
+
     // A query that returns no results
     let df2 = polarhouse::get_df_query(
         klickhouse::SelectBuilder::new(table_name)
@@ -73,18 +96,205 @@ async fn retrieve(df: DataFrame, table_name: &str, ch: impl ClientGeneric) -> an
         .get_df_query(klickhouse::SelectBuilder::new(table_name).select("*"), &ch)
         .await?;
     println!("{}", df2);
+
+    // Resolve the types of computed columns
+    let df2 = polarhouse::get_df_query(
+        format!("SELECT is_rich, NOT is_rich AS is_poor FROM {}", table_name),
+        GetOptions {
+            describe: true,
+            ..Default::default()
+        },
+        &ch,
+    )
+    .await?;
+    println!("{}", df2);
+    assert_eq!(df2.column("is_poor")?.dtype(), &DataType::Boolean);
+
+    // Bind query parameters
+    let df2 = polarhouse::get_df_query(
+        "SELECT name FROM {table:Identifier} WHERE name IN {names:Array(String)}",
+        GetOptions {
+            params: [
+                ("table".into(), table_name.into()),
+                ("names".into(), vec!["Batman", "Robin"].into()),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        },
+        &ch,
+    )
+    .await?;
+    assert_eq!(df2.height(), 1);
+
+    // Set the query id
+    let df2 = polarhouse::get_df_query(
+        klickhouse::SelectBuilder::new(table_name).select("*"),
+        GetOptions {
+            query_id: Some(format!("{}_retrieval", table_name)),
+            ..Default::default()
+        },
+        &ch,
+    )
+    .await?;
+    assert_eq!(df2.height(), 2);
+
+    // Abandon slow queries, which are stopped on the server
+    let query_id = format!("{}_abandoned_{}", table_name, http);
+    let result = polarhouse::get_df_query(
+        "SELECT sleepEachRow(1) FROM numbers(3)",
+        GetOptions {
+            timeout: Some(std::time::Duration::from_millis(500)),
+            query_id: Some(query_id.clone()),
+            ..Default::default()
+        },
+        &ch,
+    )
+    .await;
+    assert!(matches!(result, Err(polarhouse::Error::Timeout(_))));
+    let running = format!(
+        "SELECT count() AS n FROM system.processes WHERE query_id = '{}'",
+        query_id
+    );
+    let mut stopped = false;
+    for _ in 0..20 {
+        let df = polarhouse::get_df_query(running.clone(), Default::default(), &ch).await?;
+        if df.column("n")?.u64()?.get(0) == Some(0) {
+            stopped = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(stopped, "abandoned query still running");
     Ok(())
 }
-#[parameterized(http = {true}, native = {false})]
+/// Dates and decimals, whose representations differ between the formats, retrieved with the
+/// types of the table.
+async fn dates_decimals(
+    table_name: &str,
+    ch: &klickhouse::Client,
+    client: impl ClientGeneric,
+) -> anyhow::Result<()> {
+    ch.execute(format!("DROP TABLE IF EXISTS {}", table_name))
+        .await?;
+    let table = polarhouse::ClickhouseTable {
+        name: table_name.into(),
+        types: [
+            ("id", "UInt32"),
+            ("day", "Date"),
+            ("time", "DateTime('Europe/Paris')"),
+            ("price", "Nullable(Decimal(10, 2))"),
+        ]
+        .into_iter()
+        .map(|(col, type_)| Ok((col.to_string(), type_.parse()?)))
+        .collect::<anyhow::Result<_>>()?,
+    };
+    table
+        .create(
+            TableCreationOptions {
+                primary_keys: &["id"],
+                ..Default::default()
+            },
+            ch,
+        )
+        .await?;
+    let df = df!(
+        "id" => [1u32, 2],
+        "day" => [19_000i32, 20_000],
+        "time" => [1_700_000_000_000i64, 0],
+        "price" => [Some(12.34), None],
+    )?
+    .lazy()
+    .with_columns([
+        col("day").cast(DataType::Date),
+        col("time").cast(DataType::Datetime(
+            TimeUnit::Milliseconds,
+            Some("Europe/Paris".into()),
+        )),
+    ])
+    .collect()?;
+    table
+        .insert_df(df.clone(), Default::default(), &client)
+        .await?;
+    let df2 = table
+        .get_df_query(format!("SELECT * FROM {} ORDER BY id", table_name), &client)
+        .await?;
+    println!("{}", df2);
+    assert_eq!(df, df2);
+    Ok(())
+}
+
+async fn categoricals(
+    table_name: &str,
+    ch: &klickhouse::Client,
+    client: impl ClientGeneric,
+) -> anyhow::Result<()> {
+    ch.execute(format!("DROP TABLE IF EXISTS {}", table_name))
+        .await?;
+    let table = polarhouse::ClickhouseTable {
+        name: table_name.into(),
+        types: [
+            ("id", "UInt32"),
+            ("tag", "LowCardinality(String)"),
+            ("maybe", "LowCardinality(Nullable(String))"),
+        ]
+        .into_iter()
+        .map(|(col, type_)| Ok((col.to_string(), type_.parse()?)))
+        .collect::<anyhow::Result<_>>()?,
+    };
+    table
+        .create(
+            TableCreationOptions {
+                primary_keys: &["id"],
+                ..Default::default()
+            },
+            ch,
+        )
+        .await?;
+    let df = df!(
+        "id" => [1u32, 2, 3],
+        "tag" => ["a", "b", "a"],
+        "maybe" => [Some("x"), None, Some("y")],
+    )?
+    .lazy()
+    .with_columns([
+        col("tag").cast(DataType::Categorical(None, Default::default())),
+        col("maybe").cast(DataType::Categorical(None, Default::default())),
+    ])
+    .collect()?;
+    table
+        .insert_df(df.clone(), Default::default(), &client)
+        .await?;
+    let df2 = table
+        .get_df_query(format!("SELECT * FROM {} ORDER BY id", table_name), &client)
+        .await?;
+    println!("{}", df2);
+    assert!(matches!(
+        df2.column("maybe")?.dtype(),
+        DataType::Categorical(..)
+    ));
+    let strings = |df: DataFrame| {
+        df.lazy()
+            .with_columns([
+                col("tag").cast(DataType::String),
+                col("maybe").cast(DataType::String),
+            ])
+            .collect()
+    };
+    assert_eq!(strings(df)?, strings(df2)?);
+    Ok(())
+}
+
+#[parameterized(http = {true, false}, native = {false, false}, arrow = {true, true})]
 #[test_macro(tokio::test)]
-async fn test(http: bool) -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+async fn test(http: bool, arrow: bool) -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
     polars::enable_string_cache();
 
     let ch = klickhouse::Client::connect("localhost:9000", Default::default()).await?;
 
     // Setup
-    let table_name = &format!("superheroes_{:?}", http);
+    let table_name = &format!("superheroes_{:?}_{:?}", http, arrow);
     ch.execute(format!("DROP TABLE IF EXISTS {}", table_name))
         .await?;
 
@@ -110,15 +320,102 @@ async fn test(http: bool) -> anyhow::Result<()> {
             &ch,
         )
         .await?;
-    table.insert_df(df.clone(), Default::default(), &ch).await?;
+    let ch_http = HttpClientBuilder::new("http://localhost:8123")
+        .format(if arrow {
+            ColumnarFormat::ArrowStream
+        } else {
+            ColumnarFormat::Native
+        })
+        .build()?;
+    if http {
+        table
+            .insert_df(df.clone(), Default::default(), &ch_http)
+            .await?;
+    } else {
+        table.insert_df(df.clone(), Default::default(), &ch).await?;
+    }
 
     println!("Retrieve data",);
+    let dates_table = &format!("dates_{:?}_{:?}", http, arrow);
+    let categoricals_table = &format!("categoricals_{:?}_{:?}", http, arrow);
     if http {
-        let ch_http = HttpClient::new("http://localhost:8123", Some("default"), "default", None);
-        retrieve(df, table_name, ch_http).await?;
+        retrieve(df, table_name, ch_http.clone(), true).await?;
+        dates_decimals(dates_table, &ch, ch_http.clone()).await?;
+        categoricals(categoricals_table, &ch, ch_http).await?;
     } else {
-        retrieve(df.clone(), table_name, ch).await?;
+        retrieve(df.clone(), table_name, ch.clone(), false).await?;
+        dates_decimals(dates_table, &ch, ch.clone()).await?;
+        categoricals(categoricals_table, &ch, ch.clone()).await?;
     }
 
     Ok(())
 }
+
+/// Progress of concurrent queries on the same native connection, each reported to its handler.
+#[tokio::test]
+async fn native_progress() -> anyhow::Result<()> {
+    let ch = klickhouse::Client::connect("localhost:9000", Default::default()).await?;
+    let count = |rows: u64| {
+        let (handler, rx) = polarhouse::ProgressHandler::channel();
+        let query = polarhouse::get_df_query(
+            format!("SELECT count() FROM numbers({})", rows),
+            GetOptions {
+                progress: Some(handler),
+                ..Default::default()
+            },
+            &ch,
+        );
+        async move { anyhow::Ok((query.await?, rx)) }
+    };
+    let ((_, mut small), (_, mut large)) = tokio::try_join!(count(1_000), count(100_000_000))?;
+    // Each query receives its own reports, all of them by the end of its results.
+    for (reports, rows) in [(&mut small, 1_000), (&mut large, 100_000_000)] {
+        let mut last = None;
+        while let Ok(progress) = reports.try_recv() {
+            assert!(progress.read_rows <= rows);
+            last = Some(progress);
+        }
+        let last = last.expect("no progress reported");
+        assert_eq!(last.read_rows, rows);
+        assert_eq!(last.profile.map(|profile| profile.rows), Some(1));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn session() -> anyhow::Result<()> {
+    // The session options apply regardless of their order.
+    let ch = HttpClientBuilder::new("http://localhost:8123")
+        .session_timeout(std::time::Duration::from_millis(9500))
+        .session("polarhouse_session")
+        .build()?;
+    ch.execute(
+        "CREATE TEMPORARY TABLE IF NOT EXISTS numbers_tmp AS SELECT number FROM numbers(10)",
+    )
+    .await?;
+    ch.execute("SET max_threads = 1").await?;
+    let df = polarhouse::get_df_query(
+        "SELECT number FROM numbers_tmp WHERE toUInt64(getSetting('max_threads')) = 1",
+        Default::default(),
+        &ch,
+    )
+    .await?;
+    assert_eq!(df.height(), 10);
+
+    // The session exists.
+    let checked = HttpClientBuilder::new("http://localhost:8123")
+        .session_check(true)
+        .session("polarhouse_session")
+        .build()?;
+    checked.execute("SELECT * FROM numbers_tmp").await?;
+    // The session does not exist.
+    let missing = HttpClientBuilder::new("http://localhost:8123")
+        .session("polarhouse_missing_session")
+        .session_check(true)
+        .build()?;
+    match missing.execute("SELECT 1").await {
+        Err(polarhouse::Error::Server(e)) => assert_eq!(e.name, "SESSION_NOT_FOUND"),
+        r => panic!("Expected SESSION_NOT_FOUND, got {:?}", r),
+    }
+    Ok(())
+}